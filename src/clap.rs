@@ -1,17 +1,433 @@
-use clap::Parser;
+use crate::config::Config;
+use crate::i18n::{self, Lang};
+use clap::builder::PossibleValue;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{
+    ArgMatches, Args, Command, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use clap_complete::Shell;
+use std::path::Path;
 
 #[derive(Parser)]
 #[command(name = "ncmmiao")]
 #[command(author = "lkhsss")]
 #[command(version,about = "一个解密ncm文件的神秘程序 By Lkhsss", long_about = None)]
+#[command(after_help = "不指定子命令时与dump相同，例如`ncmmiao -i music -o output`")]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+    /// 输出更多日志，-v为调试信息，-vv为全部信息
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// 减少日志，-q只输出警告和错误，-qq只输出错误
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+    /// 同时将日志写入文件，不带颜色，追加写入
+    #[arg(long, name = "日志文件", global = true, env = "NCMMIAO_LOG_FILE")]
+    pub log_file: Option<String>,
+    /// 日志格式，json为每行一个JSON对象
+    #[arg(long, value_enum, name = "日志格式", global = true, env = "NCMMIAO_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// 界面语言，默认根据LANG环境变量选择
+    #[arg(long, value_enum, name = "语言", global = true, env = "NCMMIAO_LANG")]
+    pub lang: Option<Lang>,
+    /// 配置文件，默认为~/.config/ncmmiao/config.toml
+    #[arg(long, name = "配置文件", global = true, env = "NCMMIAO_CONFIG")]
+    pub config: Option<String>,
+    /// 使用配置文件中[profile.<名称>]的设置
+    #[arg(long, name = "名称", global = true, env = "NCMMIAO_PROFILE")]
+    pub profile: Option<String>,
+    /// 不指定子命令时的解密选项，与`dump`相同
+    #[command(flatten)]
+    pub dump: DumpArgs,
+}
+
+/// 解密选项
+#[derive(Args, Clone)]
+pub struct DumpArgs {
     /// 并发的最大线程数，默认为4线程
-    #[arg(short, long)]
+    #[arg(short, long, env = "NCMMIAO_WORKERS")]
     pub workers: Option<usize>,
     /// 需要解密的文件夹或文件
     #[arg(short, long, name = "输入文件/文件夹")]
     pub input: Vec<String>,
+    /// 从文件中读取需要解密的文件夹或文件，每行一个或者用\0分隔，`-`表示标准输入
+    #[arg(long, name = "列表文件")]
+    pub from_file: Vec<String>,
+    /// 只处理匹配的文件，可以多次指定；不含/的模式匹配文件名，否则匹配相对于输入文件夹的路径
+    #[arg(long, name = "模式")]
+    pub include: Vec<String>,
+    /// 跳过匹配的文件和文件夹，可以多次指定，规则与--include相同
+    #[arg(long, name = "排除模式")]
+    pub exclude: Vec<String>,
+    /// 遍历输入文件夹的最大深度，1表示不进入子文件夹
+    #[arg(long, name = "深度")]
+    pub max_depth: Option<usize>,
+    /// 跳过以.开头的隐藏文件和文件夹
+    #[arg(long, overrides_with = "no_skip_hidden")]
+    pub skip_hidden: bool,
+    /// 关闭--skip-hidden，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "skip_hidden")]
+    pub no_skip_hidden: bool,
+    /// 进入指向文件夹的符号链接，默认不进入
+    #[arg(long, overrides_with = "no_follow_links")]
+    pub follow_links: bool,
+    /// 关闭--follow-links，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "follow_links")]
+    pub no_follow_links: bool,
 
-    #[arg(short, long, name = "输出文件夹", default_value = "NcmmiaoOutput")]
+    #[arg(short, long, name = "输出文件夹", env = "NCMMIAO_OUTPUT", default_value = "NcmmiaoOutput")]
     pub output: Option<String>,
+    /// 输出文件的目录结构
+    #[arg(short, long, value_enum, name = "布局", env = "NCMMIAO_LAYOUT", default_value_t = Layout::Flat)]
+    pub layout: Layout,
+    /// 输出文件已存在时的处理方式
+    #[arg(long, value_enum, name = "策略", env = "NCMMIAO_OVERWRITE", default_value_t = OverwritePolicy::Overwrite)]
+    pub overwrite: OverwritePolicy,
+    /// 增量转换：只处理新增或有变化的文件，状态保存在输出文件夹中
+    #[arg(long, overrides_with = "no_incremental")]
+    pub incremental: bool,
+    /// 关闭--incremental，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "incremental")]
+    pub no_incremental: bool,
+    /// 指定状态文件的路径，同时启用增量转换
+    #[arg(long, name = "状态文件", env = "NCMMIAO_STATE")]
+    pub state: Option<String>,
+    /// 根据已经存在的输出文件重建状态文件，同时启用增量转换
+    #[arg(long, overrides_with = "no_rebuild_state")]
+    pub rebuild_state: bool,
+    /// 关闭--rebuild-state，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "rebuild_state")]
+    pub no_rebuild_state: bool,
+    /// 监视模式：处理完已有文件后继续监视输入文件夹，新文件写入完成后自动解密，按Ctrl-C退出
+    #[arg(long, overrides_with = "no_watch")]
+    pub watch: bool,
+    /// 关闭--watch，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "watch")]
+    pub no_watch: bool,
+    /// 监视模式中文件大小保持不变多少秒后认为写入完成
+    #[arg(long, name = "秒", env = "NCMMIAO_WATCH_DELAY", default_value_t = 2)]
+    pub watch_delay: u64,
+    /// 重新处理上次运行失败的文件，可以是`--report`生成的报告或者每行一个路径的失败列表
+    #[arg(long, name = "失败列表")]
+    pub retry: Option<String>,
+    /// 只输出每个文件的输出路径和处理方式，不写入任何文件
+    #[arg(long, conflicts_with = "watch", overrides_with = "no_dry_run")]
+    pub dry_run: bool,
+    /// 关闭--dry-run，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "dry_run")]
+    pub no_dry_run: bool,
+    /// 在stdout上输出NDJSON格式的事件流，供图形界面等程序读取，日志只输出到stderr
+    #[arg(long, conflicts_with = "dry_run", overrides_with = "no_events")]
+    pub events: bool,
+    /// 关闭--events，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "events")]
+    pub no_events: bool,
+    /// 运行结束后将每个文件的处理结果写入JSON报告
+    #[arg(long, name = "报告文件")]
+    pub report: Option<String>,
+    /// 将封面另存为图片。可以指定文件名模板，支持{filename}和{album}，
+    /// 不指定时与输出文件同名。例如`--extract-cover cover`会在每个文件夹中生成一张cover.jpg
+    #[arg(long, name = "封面文件名", num_args = 0..=1, default_missing_value = "{filename}")]
+    pub extract_cover: Option<String>,
+    /// 输出文件名模板，可以用`/`分隔文件夹。支持{title} {artist} {albumartist} {album}
+    /// {musicId} {albumId} {bitrate} {format} {filename}，例如`{albumartist}/{album}/{artist} - {title}`
+    #[arg(short, long, name = "模板", env = "NCMMIAO_TEMPLATE")]
+    pub template: Option<String>,
+    /// 模板中多个艺术家之间的分隔符
+    #[arg(long, name = "分隔符", env = "NCMMIAO_ARTIST_SEPARATOR", default_value = ", ")]
+    pub artist_separator: String,
+    /// 封面的最大边长（像素），超过时等比缩小
+    #[arg(long, name = "像素", env = "NCMMIAO_COVER_MAX_SIZE")]
+    pub cover_max_size: Option<u32>,
+    /// 重新编码JPEG封面时使用的质量(1-100)，默认为90
+    #[arg(long, name = "质量", env = "NCMMIAO_COVER_QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub cover_quality: Option<u8>,
+    /// 将封面转换为JPEG格式
+    #[arg(long, overrides_with = "no_cover_jpeg")]
+    pub cover_jpeg: bool,
+    /// 关闭--cover-jpeg，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "cover_jpeg")]
+    pub no_cover_jpeg: bool,
+    /// 去除封面图片中的元数据
+    #[arg(long, overrides_with = "no_cover_strip")]
+    pub cover_strip: bool,
+    /// 关闭--cover-strip，用于覆盖配置文件中的设置
+    #[arg(long, hide = true, overrides_with = "cover_strip")]
+    pub no_cover_strip: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// 解密NCM文件，不指定子命令时的默认操作
+    Dump(Box<DumpArgs>),
+    /// 替换或移除NCM文件内嵌的封面，音乐数据保持不变
+    Cover {
+        /// 需要处理的文件夹或文件
+        #[arg(required = true, name = "输入文件/文件夹")]
+        input: Vec<String>,
+        /// 新的封面图片，支持JPEG和PNG
+        #[arg(long, name = "图片", required_unless_present = "remove")]
+        image: Option<String>,
+        /// 移除封面
+        #[arg(long, conflicts_with = "图片")]
+        remove: bool,
+        /// 输出文件夹，不指定时直接改写原文件
+        #[arg(short, long, name = "输出文件夹")]
+        output: Option<String>,
+    },
+    /// 查看NCM文件的歌曲信息，不解密音乐数据
+    Info {
+        /// 需要查看的文件夹或文件
+        #[arg(required = true, name = "输入文件/文件夹")]
+        input: Vec<String>,
+        /// 以json格式输出
+        #[arg(long)]
+        json: bool,
+    },
+    /// 校验NCM文件是否完整，不写入任何文件
+    Check {
+        /// 需要校验的文件夹或文件
+        #[arg(required = true, name = "输入文件/文件夹")]
+        input: Vec<String>,
+        /// 并发的最大线程数，默认为4线程
+        #[arg(short, long)]
+        workers: Option<usize>,
+    },
+    /// 输出shell补全脚本
+    Completions {
+        /// shell的类型
+        #[arg(value_enum, name = "shell")]
+        shell: Shell,
+    },
+    /// 输出man手册页
+    Man,
+}
+
+impl Cli {
+    /// 解析命令行参数并合并配置文件
+    ///
+    /// 优先级从高到低为：命令行参数、环境变量、配置方案、配置文件、内置的默认值。
+    /// 帮助信息使用`--lang`、环境变量或配置文件指定的语言
+    pub fn parse_localized() -> Cli {
+        let mut args: Vec<String> = std::env::args_os()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        // 配置文件和语言需要在解析参数之前决定
+        let early = |name: &str, env: &str| {
+            find_option(&args, name).or_else(|| std::env::var(env).ok().filter(|v| !v.is_empty()))
+        };
+        let config = Config::load(
+            early("--config", "NCMMIAO_CONFIG").as_deref().map(Path::new),
+            early("--profile", "NCMMIAO_PROFILE").as_deref(),
+        );
+        let lang = early("--lang", "NCMMIAO_LANG").or_else(|| {
+            let config = config.as_ref().ok()?;
+            config.get("lang")?.as_str().map(str::to_string)
+        });
+        if let Some(lang) = lang.and_then(|lang| Lang::from_str(&lang, true).ok()) {
+            i18n::set(lang);
+        }
+
+        let mut command = Cli::localized_command();
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => command.error(ErrorKind::InvalidValue, e).exit(),
+        };
+        let mut matches = Cli::matches_from(&command, &args).unwrap_or_else(|e| e.exit());
+        let extra = match config.to_args(&command, &matches) {
+            Ok(extra) => extra,
+            Err(e) => command.error(ErrorKind::InvalidValue, e).exit(),
+        };
+        if !extra.is_empty() {
+            let end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+            args.splice(end..end, extra);
+            matches = Cli::matches_from(&command, &args).unwrap_or_else(|e| e.exit());
+        }
+        match Cli::from_arg_matches(&matches) {
+            Ok(cli) => cli,
+            Err(e) => e.exit(),
+        }
+    }
+}
+
+impl Cli {
+    /// 翻译后的命令定义，用于解析参数以及生成补全脚本和手册页
+    ///
+    /// 不指定子命令时的解密选项与`dump`相同，同样显示在帮助中
+    pub fn localized_command() -> Command {
+        localize(Cli::command())
+    }
+
+    /// 解析参数
+    ///
+    /// 全局选项可以写在子命令之前或之后，不指定子命令时的解密选项不能与子命令一起使用
+    pub fn matches_from(command: &Command, args: &[String]) -> Result<ArgMatches, clap::Error> {
+        let matches = command.clone().try_get_matches_from(args)?;
+        let Some(name) = matches.subcommand_name() else {
+            return Ok(matches);
+        };
+        let dump_arg = command.get_arguments().find(|arg| {
+            !arg.is_global_set()
+                && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        });
+        match dump_arg {
+            Some(arg) => {
+                let flag = match arg.get_long() {
+                    Some(long) => format!("--{}", long),
+                    None => arg.get_id().to_string(),
+                };
+                Err(command.clone().error(
+                    ErrorKind::ArgumentConflict,
+                    tr!("子命令{}不能与{}一起使用", name, flag),
+                ))
+            }
+            None => Ok(matches),
+        }
+    }
+}
+
+/// 在解析之前查找`--name value`或者`--name=value`形式的参数
+fn find_option(args: &[String], name: &str) -> Option<String> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            break;
+        }
+        match arg.strip_prefix(name) {
+            Some("") => return iter.next().cloned(),
+            Some(value) => {
+                if let Some(value) = value.strip_prefix('=') {
+                    return Some(value.to_string());
+                }
+            }
+            None => {}
+        }
+    }
+    None
+}
+
+/// 翻译命令和子命令的说明、参数的帮助和参数名
+pub fn localize(mut command: Command) -> Command {
+    if let Some(about) = command.get_about().and_then(|s| i18n::lookup(&s.to_string())) {
+        command = command.about(about);
+    }
+    if let Some(help) = command.get_after_help().and_then(|s| i18n::lookup(&s.to_string())) {
+        command = command.after_help(help);
+    }
+    let ids: Vec<_> = command.get_arguments().map(|arg| arg.get_id().clone()).collect();
+    for id in ids {
+        command = command.mut_arg(id, |mut arg| {
+            if let Some(help) = arg.get_help().and_then(|s| i18n::lookup(&s.to_string())) {
+                arg = arg.help(help);
+            }
+            if let Some(help) = arg.get_long_help().and_then(|s| i18n::lookup(&s.to_string())) {
+                arg = arg.long_help(help);
+            }
+            let names = arg
+                .get_value_names()
+                .and_then(|names| names.iter().map(|name| i18n::lookup(name)).collect::<Option<Vec<_>>>());
+            if let Some(names) = names {
+                arg = arg.value_names(names);
+            }
+            arg
+        });
+    }
+    let names: Vec<String> = command
+        .get_subcommands()
+        .map(|sub| sub.get_name().to_string())
+        .collect();
+    for name in names {
+        command = command.mut_subcommand(name, localize);
+    }
+    command
+}
+
+/// 输出文件的目录结构
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Layout {
+    /// 全部输出到输出文件夹中
+    #[default]
+    Flat,
+    /// 在输出文件夹中保留输入文件夹的目录结构
+    Mirror,
+    /// 输出到源文件所在的文件夹
+    Source,
+}
+
+impl ValueEnum for Layout {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Layout::Flat, Layout::Mirror, Layout::Source]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Layout::Flat => PossibleValue::new("flat").help(tr!("全部输出到输出文件夹中")),
+            Layout::Mirror => {
+                PossibleValue::new("mirror").help(tr!("在输出文件夹中保留输入文件夹的目录结构"))
+            }
+            Layout::Source => PossibleValue::new("source").help(tr!("输出到源文件所在的文件夹")),
+        })
+    }
+}
+
+/// 输出文件已存在时的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverwritePolicy {
+    /// 跳过
+    Skip,
+    /// 直接覆盖
+    #[default]
+    Overwrite,
+    /// 在文件名后添加数字
+    Rename,
+    /// 源文件比输出文件新时才覆盖
+    Newer,
+}
+
+impl ValueEnum for OverwritePolicy {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            OverwritePolicy::Skip,
+            OverwritePolicy::Overwrite,
+            OverwritePolicy::Rename,
+            OverwritePolicy::Newer,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            OverwritePolicy::Skip => PossibleValue::new("skip").help(tr!("跳过")),
+            OverwritePolicy::Overwrite => PossibleValue::new("overwrite").help(tr!("直接覆盖")),
+            OverwritePolicy::Rename => PossibleValue::new("rename").help(tr!("在文件名后添加数字")),
+            OverwritePolicy::Newer => {
+                PossibleValue::new("newer").help(tr!("源文件比输出文件新时才覆盖"))
+            }
+        })
+    }
+}
+
+/// 日志格式
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// 带颜色的文本
+    #[default]
+    Text,
+    /// 每行一个JSON对象，包含时间、等级、消息、文件、阶段和线程
+    Json,
+}
+
+impl ValueEnum for LogFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[LogFormat::Text, LogFormat::Json]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            LogFormat::Text => PossibleValue::new("text").help(tr!("带颜色的文本")),
+            LogFormat::Json => PossibleValue::new("json")
+                .help(tr!("每行一个JSON对象，包含时间、等级、消息、文件、阶段和线程")),
+        })
+    }
 }
@@ -0,0 +1,157 @@
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// 配置文件的默认位置：`$XDG_CONFIG_HOME/ncmmiao/config.toml`，
+/// 没有设置时为`~/.config/ncmmiao/config.toml`，Windows上为`%APPDATA%\ncmmiao\config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(windows) => PathBuf::from(std::env::var_os("APPDATA")?),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("ncmmiao").join("config.toml"))
+}
+
+/// 配置文件中的默认值，已经合并了选择的配置方案
+///
+/// 键为命令行选项的长名称，例如`cover-max-size`，也可以写成`cover_max_size`
+#[derive(Debug, Default)]
+pub struct Config {
+    values: Vec<(String, Value)>,
+}
+
+impl Config {
+    /// 读取配置文件并合并`[profile.<名称>]`中的设置
+    ///
+    /// 没有指定路径并且默认位置没有配置文件时返回空的配置
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path().filter(|path| path.is_file()) {
+                Some(path) => path,
+                None => {
+                    return match profile {
+                        Some(name) => Err(tr!("没有找到配置文件，无法使用配置方案[{}]", name)),
+                        None => Ok(Config::default()),
+                    }
+                }
+            },
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => return Err(tr!("无法读取配置文件[{}]: {}", path.display(), e)),
+        };
+        let mut table: Table = match text.parse() {
+            Ok(table) => table,
+            Err(e) => return Err(tr!("配置文件格式错误[{}]: {}", path.display(), e)),
+        };
+        let profiles = table.remove("profile");
+        let mut config = Config::default();
+        config.merge(table)?;
+        if let Some(name) = profile {
+            let profile = match profiles.as_ref().and_then(|p| p.get(name)) {
+                Some(Value::Table(profile)) => profile.clone(),
+                _ => return Err(tr!("配置文件中没有配置方案[{}]", name)),
+            };
+            config.merge(profile)?;
+        }
+        Ok(config)
+    }
+
+    /// 后合并的值覆盖先合并的值
+    fn merge(&mut self, table: Table) -> Result<(), String> {
+        for (key, value) in table {
+            let key = key.replace('_', "-");
+            if key == "config" || key == "profile" {
+                return Err(tr!("配置文件中不能设置{}", key));
+            }
+            self.values.retain(|(k, _)| *k != key);
+            self.values.push((key, value));
+        }
+        Ok(())
+    }
+
+    /// 配置中的值
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// 把配置转换成命令行参数，追加在用户的参数后面
+    ///
+    /// 已经在命令行或者环境变量中指定的选项，以及与它们冲突的选项都会跳过，
+    /// 命令行中的`--no-<选项>`同样会跳过对应的开关；开关为`false`时传入`--no-<选项>`。
+    /// 使用`dump`以外的子命令时只使用全局选项
+    pub fn to_args(&self, command: &Command, matches: &ArgMatches) -> Result<Vec<String>, String> {
+        let mut command = command.clone();
+        command.build();
+        let sub = matches.subcommand().map(|(_, m)| m);
+        // dump子命令和不指定子命令时的选项相同
+        let (command, only_global) = match matches.subcommand_name() {
+            Some("dump") => (command.find_subcommand("dump").unwrap().clone(), false),
+            Some(_) => (command, true),
+            None => (command, false),
+        };
+        let explicit = |id: &str| {
+            let source = sub
+                .and_then(|m| m.value_source(id))
+                .or_else(|| matches.value_source(id));
+            matches!(
+                source,
+                Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+            )
+        };
+        let mut args = Vec::new();
+        for (key, value) in &self.values {
+            let arg = match command.get_arguments().find(|arg| arg.get_long() == Some(key)) {
+                Some(arg) => arg,
+                None => return Err(tr!("配置文件中有未知选项: {}", key)),
+            };
+            if only_global && !arg.is_global_set() {
+                continue;
+            }
+            // 开关和对应的--no-开关互相覆盖
+            let negation = match key.strip_prefix("no-") {
+                Some(key) => key.to_string(),
+                None => format!("no-{}", key),
+            };
+            let negation = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(negation.as_str()));
+            if explicit(arg.get_id().as_str())
+                || negation.is_some_and(|arg| explicit(arg.get_id().as_str()))
+                || command
+                    .get_arg_conflicts_with(arg)
+                    .iter()
+                    .any(|other| explicit(other.get_id().as_str()))
+            {
+                continue;
+            }
+            let flag = format!("--{}", key);
+            let values = match value {
+                Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    Value::Boolean(true) => args.push(flag.clone()),
+                    Value::Boolean(false) => {
+                        if let Some(negation) = negation.and_then(|arg| arg.get_long()) {
+                            args.push(format!("--{}", negation));
+                        }
+                    }
+                    Value::Integer(n) if matches!(arg.get_action(), ArgAction::Count) => {
+                        args.extend(std::iter::repeat_n(flag.clone(), (*n).max(0) as usize));
+                    }
+                    Value::String(s) => args.push(format!("{}={}", flag, s)),
+                    Value::Integer(n) => args.push(format!("{}={}", flag, n)),
+                    Value::Float(n) => args.push(format!("{}={}", flag, n)),
+                    _ => return Err(tr!("配置项{}的值无效", key)),
+                }
+            }
+        }
+        Ok(args)
+    }
+}
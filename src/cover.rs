@@ -0,0 +1,71 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+#[allow(unused_imports)]
+use log::{debug, trace};
+use std::io::Cursor;
+
+/// 默认的JPEG质量
+const DEFAULT_QUALITY: u8 = 90;
+
+/// 封面处理选项，全部为默认值时保持原始封面
+#[derive(Clone, Default, Debug)]
+pub struct CoverOptions {
+    /// 最大边长，超过时等比缩小
+    pub max_size: Option<u32>,
+    /// JPEG质量，1~100
+    pub quality: Option<u8>,
+    /// 转换为JPEG格式
+    pub jpeg: bool,
+    /// 去除图片中的元数据（EXIF、ICC等）
+    pub strip: bool,
+}
+
+impl CoverOptions {
+    /// 是否保持原始封面
+    pub fn is_original(&self) -> bool {
+        self.max_size.is_none() && self.quality.is_none() && !self.jpeg && !self.strip
+    }
+}
+
+/// 按照选项处理封面
+///
+/// 不需要处理时原样返回，避免重复压缩
+pub fn process(data: &[u8], options: &CoverOptions) -> Result<Vec<u8>, image::ImageError> {
+    if data.is_empty() || options.is_original() {
+        return Ok(data.to_vec());
+    }
+    trace!("处理封面");
+    let format = image::guess_format(data)?;
+    let mut img = image::load_from_memory_with_format(data, format)?;
+
+    let mut changed = options.strip;
+    if let Some(max_size) = options.max_size {
+        if img.width() > max_size || img.height() > max_size {
+            debug!("{}", tr!("缩小封面: {}x{} -> 最大边长{}", img.width(), img.height(), max_size));
+            img = img.resize(max_size, max_size, FilterType::Lanczos3);
+            changed = true;
+        }
+    }
+
+    // 原本是JPEG或者要求转换时输出JPEG，其他格式输出PNG
+    let to_jpeg = options.jpeg || format == ImageFormat::Jpeg;
+    if to_jpeg && (format != ImageFormat::Jpeg || options.quality.is_some()) {
+        changed = true;
+    }
+    if !changed {
+        return Ok(data.to_vec());
+    }
+
+    let mut buf = Vec::new();
+    if to_jpeg {
+        let quality = options.quality.unwrap_or(DEFAULT_QUALITY);
+        // JPEG不支持透明通道
+        let rgb = DynamicImage::ImageRgb8(img.to_rgb8());
+        JpegEncoder::new_with_quality(&mut buf, quality).encode_image(&rgb)?;
+    } else {
+        img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
+    }
+    debug!("{}", tr!("封面大小: {} -> {} 字节", data.len(), buf.len()));
+    Ok(buf)
+}
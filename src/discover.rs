@@ -0,0 +1,264 @@
+use crate::clap::Layout;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use walkdir::{DirEntry, WalkDir}; //遍历目录
+
+/// 找到的ncm文件
+#[derive(Debug, Clone)]
+pub struct InputFile {
+    /// 文件路径
+    pub path: String,
+    /// 相对于输入文件夹的子文件夹，直接输入的文件为空
+    pub subdir: PathBuf,
+    /// 指定的输出文件夹，重试上次失败的文件时沿用上次的输出文件夹
+    pub output: Option<PathBuf>,
+}
+
+impl InputFile {
+    /// 根据布局计算该文件的输出文件夹
+    pub fn output_dir(&self, outputdir: &Path, layout: Layout) -> PathBuf {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+        match layout {
+            Layout::Flat => outputdir.to_path_buf(),
+            Layout::Mirror => outputdir.join(&self.subdir),
+            Layout::Source => Path::new(&self.path)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// 判断一个文件是否需要解密，批量解密和监视模式使用同样的规则
+pub fn is_ncm(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "ncm")
+}
+
+/// 遍历输入文件夹时的筛选规则，批量解密和监视模式使用同样的规则
+#[derive(Debug, Default, Clone)]
+pub struct DiscoverOptions {
+    /// 不为空时只处理匹配的文件
+    include: Option<GlobSet>,
+    /// 跳过匹配的文件和文件夹
+    exclude: Option<GlobSet>,
+    /// 最大遍历深度，1表示只处理输入文件夹本身中的文件
+    pub max_depth: Option<usize>,
+    /// 跳过以`.`开头的文件和文件夹
+    pub skip_hidden: bool,
+    /// 进入指向文件夹的符号链接
+    pub follow_links: bool,
+}
+
+impl DiscoverOptions {
+    /// 编译`--include`和`--exclude`中的模式
+    ///
+    /// 不含`/`的模式匹配文件名，否则匹配相对于输入文件夹的路径
+    pub fn new(include: &[String], exclude: &[String]) -> Result<DiscoverOptions, String> {
+        Ok(DiscoverOptions {
+            include: build_globs(include)?,
+            exclude: build_globs(exclude)?,
+            ..Default::default()
+        })
+    }
+
+    /// 相对于输入文件夹的路径是否被排除，检查每一级路径
+    fn excluded(&self, relative: &Path) -> bool {
+        let Some(exclude) = &self.exclude else {
+            return false;
+        };
+        relative
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| matches_glob(exclude, path))
+    }
+
+    /// 文件夹是否需要跳过，输入文件夹本身不会被跳过
+    fn skip_dir(&self, relative: &Path) -> bool {
+        !relative.as_os_str().is_empty()
+            && ((self.skip_hidden && is_hidden(relative)) || self.excluded(relative))
+    }
+
+    /// 相对于输入文件夹的文件是否需要处理
+    pub fn accepts(&self, relative: &Path) -> bool {
+        if !is_ncm(relative) || self.excluded(relative) {
+            return false;
+        }
+        if self
+            .include
+            .as_ref()
+            .is_some_and(|include| !matches_glob(include, relative))
+        {
+            return false;
+        }
+        if self.skip_hidden && relative.components().any(|c| is_hidden(Path::new(&c))) {
+            return false;
+        }
+        self.max_depth
+            .is_none_or(|depth| relative.components().count() <= depth)
+    }
+}
+
+fn build_globs(patterns: &[String]) -> Result<Option<GlobSet>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => builder.add(glob),
+            Err(e) => return Err(tr!("无效的匹配模式[{}]: {}", pattern, e)),
+        };
+    }
+    match builder.build() {
+        Ok(set) => Ok(Some(set)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// 同时尝试匹配文件名和完整的相对路径
+fn matches_glob(set: &GlobSet, relative: &Path) -> bool {
+    set.is_match(relative) || relative.file_name().is_some_and(|name| set.is_match(name))
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// 读取路径列表，`-`表示标准输入
+pub fn read_list(path: &str) -> std::io::Result<Vec<String>> {
+    if path == "-" {
+        parse_list(std::io::stdin().lock())
+    } else {
+        parse_list(std::fs::File::open(path)?)
+    }
+}
+
+/// 解析路径列表
+///
+/// 内容中有`\0`时按`\0`分隔（`find -print0`），否则每行一个路径，空行会被忽略。
+/// 不是有效UTF-8的路径只输出警告并跳过，与遍历文件夹时的规则相同
+pub fn parse_list<R: Read>(mut reader: R) -> std::io::Result<Vec<String>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let separator = if data.contains(&0) { b'\0' } else { b'\n' };
+    Ok(data
+        .split(|byte| *byte == separator)
+        .map(|entry| entry.strip_suffix(b"\r").unwrap_or(entry))
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match String::from_utf8(entry.to_vec()) {
+            Ok(path) => Some(path),
+            Err(_) => {
+                warn!(
+                    "{}",
+                    tr!(
+                        "[{}]不是有效的UTF-8路径，已跳过",
+                        String::from_utf8_lossy(entry)
+                    )
+                );
+                None
+            }
+        })
+        .collect())
+}
+
+/// 去掉指向同一个源文件的重复输入，只保留第一次出现的
+///
+/// 例如`-i in -i in/a.ncm`会两次找到`in/a.ncm`
+pub fn dedup(files: Vec<InputFile>) -> Vec<InputFile> {
+    let mut seen = HashSet::new();
+    files
+        .into_iter()
+        .filter(|file| {
+            let canonical =
+                std::fs::canonicalize(&file.path).unwrap_or_else(|_| PathBuf::from(&file.path));
+            let first = seen.insert(canonical);
+            if !first {
+                debug!("{}", tr!("[{}]重复输入，已忽略", file.path));
+            }
+            first
+        })
+        .collect()
+}
+
+/// 解析传入的每一个路径，收集其中所有的ncm文件
+///
+/// 无法读取的文件夹只输出警告，不会影响其他文件
+pub fn collect_files(input: &[String], options: &DiscoverOptions) -> Vec<InputFile> {
+    let mut files = Vec::new();
+    for arg in input {
+        //解析传入的每一个路径：文件or文件夹
+        let path = Path::new(arg);
+
+        if path.is_file() {
+            // 当后缀符合为ncm时才加入列表，直接输入的文件只检查匹配模式
+            let name = Path::new(path.file_name().unwrap_or_default());
+            if is_ncm(path)
+                && !options.excluded(name)
+                && options
+                    .include
+                    .as_ref()
+                    .is_none_or(|include| matches_glob(include, name))
+            {
+                files.push(InputFile {
+                    path: arg.to_owned(),
+                    subdir: PathBuf::new(),
+                    output: None,
+                });
+            }
+        } else if path.is_dir() {
+            let relative = |entry: &DirEntry| {
+                entry
+                    .path()
+                    .strip_prefix(path)
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default()
+            };
+            let mut walker = WalkDir::new(path).follow_links(options.follow_links);
+            if let Some(depth) = options.max_depth {
+                walker = walker.max_depth(depth);
+            }
+            let walker = walker.into_iter().filter_entry(|entry| {
+                !entry.file_type().is_dir() || !options.skip_dir(&relative(entry))
+            });
+            for entry in walker {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        let place = e.path().unwrap_or(path).display().to_string();
+                        warn!("{}", tr!("无法读取[{}]: {}", place, e));
+                        continue;
+                    }
+                };
+                if entry.file_type().is_dir() || !options.accepts(&relative(&entry)) {
+                    continue;
+                }
+                let filepath = entry.into_path();
+                let Some(filepath_str) = filepath.to_str() else {
+                    warn!(
+                        "{}",
+                        tr!("[{}]不是有效的UTF-8路径，已跳过", filepath.display())
+                    );
+                    continue;
+                };
+                // 保留输入文件夹之下的目录结构
+                let subdir = filepath
+                    .parent()
+                    .and_then(|parent| parent.strip_prefix(path).ok())
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                files.push(InputFile {
+                    path: filepath_str.to_string(),
+                    subdir,
+                    output: None,
+                });
+            }
+        }
+    }
+    files
+}
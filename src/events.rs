@@ -0,0 +1,134 @@
+use crate::ncmdump::Stage;
+use crate::report::{ErrorReport, FileReport, FileStatus, Totals};
+use serde_derive::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 事件流的格式版本，字段有不兼容的变化时增加
+pub const EVENTS_VERSION: u32 = 1;
+
+/// 两次进度事件之间至少解密的字节数
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 在stdout上输出事件流
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// 一个事件，`path`都是源文件的绝对路径
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// 找到需要处理的文件
+    Discovered { path: &'a str, bytes: u64 },
+    /// 开始处理，worker为线程编号
+    Started { path: &'a str, worker: Option<usize> },
+    /// 进入新的解密阶段
+    Stage { path: &'a str, stage: Stage },
+    /// 已经解密的字节数
+    Progress { path: &'a str, bytes: u64, total: u64 },
+    /// 处理完成，包括跳过和没有变化的文件
+    Finished {
+        path: &'a str,
+        status: FileStatus,
+        output: Option<&'a str>,
+        bytes_out: u64,
+        duration_ms: u64,
+    },
+    /// 处理失败，`error.code`见`NcmError::code`
+    Failed {
+        path: &'a str,
+        error: Option<&'a ErrorReport>,
+        duration_ms: u64,
+    },
+    /// 因为中断而没有处理
+    Cancelled { path: &'a str },
+    /// 运行结束
+    Summary {
+        interrupted: bool,
+        duration_ms: u64,
+        exit_code: u8,
+        totals: &'a Totals,
+    },
+}
+
+/// 每一行的外层，所有事件都带有版本和时间
+#[derive(Serialize)]
+struct Line<'a> {
+    version: u32,
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// 输出一个事件，没有启用事件流时什么都不做
+pub fn emit(event: Event) {
+    if !enabled() {
+        return;
+    }
+    let line = Line {
+        version: EVENTS_VERSION,
+        timestamp: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        event: &event,
+    };
+    let mut stdout = std::io::stdout().lock();
+    let _ = serde_json::to_writer(&mut stdout, &line);
+    let _ = stdout.write_all(b"\n");
+    let _ = stdout.flush();
+}
+
+/// 根据文件的处理结果输出结束事件
+pub fn file_done(report: &FileReport) {
+    emit(match report.status {
+        FileStatus::Failed => Event::Failed {
+            path: &report.path,
+            error: report.error.as_ref(),
+            duration_ms: report.duration_ms,
+        },
+        FileStatus::Cancelled => Event::Cancelled { path: &report.path },
+        status => Event::Finished {
+            path: &report.path,
+            status,
+            output: report.output.as_deref(),
+            bytes_out: report.bytes_out,
+            duration_ms: report.duration_ms,
+        },
+    });
+}
+
+/// 合并解密进度，每解密`PROGRESS_STEP`字节输出一次进度事件
+pub struct BytesProgress {
+    path: String,
+    total: u64,
+    bytes: u64,
+    reported: u64,
+}
+
+impl BytesProgress {
+    pub fn new(path: &str, total: u64) -> BytesProgress {
+        BytesProgress {
+            path: path.to_string(),
+            total,
+            bytes: 0,
+            reported: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.bytes - self.reported >= PROGRESS_STEP {
+            self.reported = self.bytes;
+            emit(Event::Progress {
+                path: &self.path,
+                bytes: self.bytes,
+                total: self.total,
+            });
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! 程序的退出码，方便脚本判断运行结果
+
+/// 所有文件都处理成功（包括跳过和没有变化的文件）
+pub const SUCCESS: u8 = 0;
+/// 有文件处理失败
+pub const FAILED: u8 = 1;
+/// 参数错误，与clap解析参数失败时的退出码一致
+pub const INVALID_ARGS: u8 = 2;
+/// 没有找到需要处理的文件
+pub const NOT_FOUND: u8 = 3;
+/// 被Ctrl-C或SIGTERM中断，与shell中被SIGINT结束的程序一致
+pub const INTERRUPTED: u8 = 130;
+
+/// 批量解密结束时的退出码
+///
+/// 中断优先，其次是运行中已经确定的错误（参数错误、没有找到文件），最后看有没有失败的文件
+pub fn summary(code: u8, interrupted: bool, failed: usize) -> u8 {
+    if interrupted {
+        INTERRUPTED
+    } else if failed > 0 && code == SUCCESS {
+        FAILED
+    } else {
+        code
+    }
+}
@@ -0,0 +1,318 @@
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::sync::OnceLock;
+
+/// 翻译一条消息，用法与`format!`相同，但只支持`{}`
+///
+/// 消息本身就是中文文本，其他语言在目录中查找，找不到时使用中文
+macro_rules! tr {
+    ($msg:literal) => {
+        $crate::i18n::text($msg)
+    };
+    ($msg:literal, $($arg:expr),+ $(,)?) => {
+        $crate::i18n::format(
+            $crate::i18n::text($msg),
+            &[$(&$arg as &dyn std::fmt::Display),+],
+        )
+    };
+}
+
+/// 界面语言
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Lang {
+    #[value(name = "zh-CN")]
+    ZhCn,
+    #[value(name = "en")]
+    En,
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+/// 设置界面语言，只能在输出任何消息之前调用一次
+pub fn set(lang: Lang) {
+    let _ = LANG.set(lang);
+}
+
+/// 当前的界面语言，没有设置时根据环境变量决定
+pub fn current() -> Lang {
+    *LANG.get_or_init(detect)
+}
+
+/// 按照LC_ALL、LC_MESSAGES、LANG的顺序读取语言环境
+///
+/// 没有设置或者为C/POSIX时使用中文
+fn detect() -> Lang {
+    for var in ["LC_ALL", "LC_MESSAGES", "LANG"] {
+        if let Ok(value) = std::env::var(var) {
+            if !value.is_empty() {
+                return from_locale(&value);
+            }
+        }
+    }
+    Lang::ZhCn
+}
+
+/// 根据`zh_CN.UTF-8`这样的语言环境选择语言
+pub fn from_locale(locale: &str) -> Lang {
+    let lang = locale.split(['.', '@']).next().unwrap_or_default();
+    if lang.starts_with("zh") || lang == "C" || lang == "POSIX" {
+        Lang::ZhCn
+    } else {
+        Lang::En
+    }
+}
+
+/// 查找消息的翻译
+pub fn text(msg: &'static str) -> &'static str {
+    lookup(msg).unwrap_or(msg)
+}
+
+/// 查找运行时得到的消息的翻译，例如命令行的帮助信息
+pub fn lookup(msg: &str) -> Option<&'static str> {
+    match current() {
+        Lang::ZhCn => None,
+        Lang::En => EN.get(msg).copied(),
+    }
+}
+
+/// 用参数依次替换模板中的`{}`，`{{`和`}}`表示括号本身
+pub fn format(template: &str, args: &[&dyn Display]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                out.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if let Some(arg) = args.next() {
+                    let _ = write!(out, "{}", arg);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+lazy_static! {
+    static ref EN: HashMap<&'static str, &'static str> = EN_CATALOG.iter().copied().collect();
+}
+
+/// 英文目录，键为中文原文
+pub const EN_CATALOG: &[(&str, &str)] = &[
+    // 命令行
+    ("一个解密ncm文件的神秘程序 By Lkhsss", "A magic tool to decrypt ncm files By Lkhsss"),
+    ("输出更多日志，-v为调试信息，-vv为全部信息", "Show more logs, -v for debug messages, -vv for everything"),
+    ("减少日志，-q只输出警告和错误，-qq只输出错误", "Show fewer logs, -q for warnings and errors only, -qq for errors only"),
+    ("同时将日志写入文件，不带颜色，追加写入", "Also append the logs to a file, without colors"),
+    ("日志格式，json为每行一个JSON对象", "Log format, json prints one JSON object per line"),
+    ("带颜色的文本", "Coloured text"),
+    ("每行一个JSON对象，包含时间、等级、消息、文件、阶段和线程", "One JSON object per line with timestamp, level, message, file, stage and worker"),
+    ("界面语言，默认根据LANG环境变量选择", "UI language, chosen from the LANG environment variable by default"),
+    ("配置文件，默认为~/.config/ncmmiao/config.toml", "Config file, ~/.config/ncmmiao/config.toml by default"),
+    ("使用配置文件中[profile.<名称>]的设置", "Use the settings from [profile.<NAME>] in the config file"),
+    ("并发的最大线程数，默认为4线程", "Maximum number of worker threads, 4 by default"),
+    ("需要解密的文件夹或文件", "Files or folders to decrypt"),
+    ("从文件中读取需要解密的文件夹或文件，每行一个或者用\\0分隔，`-`表示标准输入", "Read the files or folders to decrypt from a file, one per line or separated by \\0, `-` reads stdin"),
+    ("只处理匹配的文件，可以多次指定；不含/的模式匹配文件名，否则匹配相对于输入文件夹的路径", "Only process matching files, can be repeated; patterns without / match the file name, otherwise the path relative to the input folder"),
+    ("跳过匹配的文件和文件夹，可以多次指定，规则与--include相同", "Skip matching files and folders, can be repeated, same rules as --include"),
+    ("遍历输入文件夹的最大深度，1表示不进入子文件夹", "Maximum depth when walking input folders, 1 means no subfolders"),
+    ("跳过以.开头的隐藏文件和文件夹", "Skip hidden files and folders starting with ."),
+    ("进入指向文件夹的符号链接，默认不进入", "Follow symlinks to folders, not followed by default"),
+    ("无效的匹配模式[{}]: {}", "Invalid pattern [{}]: {}"),
+    ("[{}]不是有效的UTF-8路径，已跳过", "[{}] is not a valid UTF-8 path, skipped"),
+    ("[{}]重复输入，已忽略", "[{}] was given more than once, ignored"),
+    ("输出文件的目录结构", "Folder structure of the output files"),
+    ("输出文件已存在时的处理方式", "What to do when the output file already exists"),
+    ("增量转换：只处理新增或有变化的文件，状态保存在输出文件夹中", "Incremental mode: only process new or changed files, the state is kept in the output folder"),
+    ("指定状态文件的路径，同时启用增量转换", "Path of the state file, implies incremental mode"),
+    ("根据已经存在的输出文件重建状态文件，同时启用增量转换", "Rebuild the state file from existing output files, implies incremental mode"),
+    ("监视模式：处理完已有文件后继续监视输入文件夹，新文件写入完成后自动解密，按Ctrl-C退出", "Watch mode: after the existing files, keep watching the input folders and decrypt new files once they are fully written. Press Ctrl-C to quit"),
+    ("监视模式中文件大小保持不变多少秒后认为写入完成", "In watch mode, seconds the file size must stay unchanged before the file is considered complete"),
+    ("重新处理上次运行失败的文件，可以是`--report`生成的报告或者每行一个路径的失败列表", "Reprocess the files that failed last time, from a `--report` report or a failed list with one path per line"),
+    ("只输出每个文件的输出路径和处理方式，不写入任何文件", "Only print the output path and action for every file, without writing anything"),
+    ("在stdout上输出NDJSON格式的事件流，供图形界面等程序读取，日志只输出到stderr", "Emit an NDJSON event stream on stdout for GUIs and other programs, logs go to stderr only"),
+    ("运行结束后将每个文件的处理结果写入JSON报告", "Write the result of every file to a JSON report when the run ends"),
+    ("将封面另存为图片。可以指定文件名模板，支持{filename}和{album}， 不指定时与输出文件同名。例如`--extract-cover cover`会在每个文件夹中生成一张cover.jpg", "Also save the cover as an image. Accepts a file name template with {filename} and {album}; defaults to the output file name. For example `--extract-cover cover` writes one cover.jpg per folder"),
+    ("输出文件名模板，可以用`/`分隔文件夹。支持{title} {artist} {albumartist} {album} {musicId} {albumId} {bitrate} {format} {filename}，例如`{albumartist}/{album}/{artist} - {title}`", "Output file name template, `/` separates folders. Supports {title} {artist} {albumartist} {album} {musicId} {albumId} {bitrate} {format} {filename}, e.g. `{albumartist}/{album}/{artist} - {title}`"),
+    ("模板中多个艺术家之间的分隔符", "Separator between multiple artists in the template"),
+    ("封面的最大边长（像素），超过时等比缩小", "Maximum cover edge length in pixels, larger covers are scaled down"),
+    ("重新编码JPEG封面时使用的质量(1-100)，默认为90", "Quality used when re-encoding JPEG covers (1-100), 90 by default"),
+    ("将封面转换为JPEG格式", "Convert the cover to JPEG"),
+    ("去除封面图片中的元数据", "Strip metadata from the cover image"),
+    ("关闭--skip-hidden，用于覆盖配置文件中的设置", "Turn off --skip-hidden, overriding the config file"),
+    ("关闭--follow-links，用于覆盖配置文件中的设置", "Turn off --follow-links, overriding the config file"),
+    ("关闭--incremental，用于覆盖配置文件中的设置", "Turn off --incremental, overriding the config file"),
+    ("关闭--rebuild-state，用于覆盖配置文件中的设置", "Turn off --rebuild-state, overriding the config file"),
+    ("关闭--watch，用于覆盖配置文件中的设置", "Turn off --watch, overriding the config file"),
+    ("关闭--dry-run，用于覆盖配置文件中的设置", "Turn off --dry-run, overriding the config file"),
+    ("关闭--events，用于覆盖配置文件中的设置", "Turn off --events, overriding the config file"),
+    ("关闭--cover-jpeg，用于覆盖配置文件中的设置", "Turn off --cover-jpeg, overriding the config file"),
+    ("关闭--cover-strip，用于覆盖配置文件中的设置", "Turn off --cover-strip, overriding the config file"),
+    ("子命令{}不能与{}一起使用", "The subcommand {} cannot be used with {}"),
+    ("不指定子命令时与dump相同，例如`ncmmiao -i music -o output`", "Without a subcommand ncmmiao behaves like dump, e.g. `ncmmiao -i music -o output`"),
+    ("解密NCM文件，不指定子命令时的默认操作", "Decrypt NCM files, the default when no subcommand is given"),
+    ("输出shell补全脚本", "Print a shell completion script"),
+    ("shell的类型", "Shell to generate the script for"),
+    ("输出man手册页", "Print the man page"),
+    ("替换或移除NCM文件内嵌的封面，音乐数据保持不变", "Replace or remove the cover embedded in NCM files, leaving the music data untouched"),
+    ("需要处理的文件夹或文件", "Files or folders to process"),
+    ("新的封面图片，支持JPEG和PNG", "New cover image, JPEG or PNG"),
+    ("移除封面", "Remove the cover"),
+    ("输出文件夹，不指定时直接改写原文件", "Output folder, the original files are modified in place if omitted"),
+    ("查看NCM文件的歌曲信息，不解密音乐数据", "Show the song information of NCM files without decrypting the music"),
+    ("需要查看的文件夹或文件", "Files or folders to inspect"),
+    ("以json格式输出", "Print as JSON"),
+    ("校验NCM文件是否完整，不写入任何文件", "Check that NCM files are intact, without writing anything"),
+    ("需要校验的文件夹或文件", "Files or folders to check"),
+    ("全部输出到输出文件夹中", "Put everything directly in the output folder"),
+    ("在输出文件夹中保留输入文件夹的目录结构", "Keep the input folder structure inside the output folder"),
+    ("输出到源文件所在的文件夹", "Write next to the source file"),
+    ("跳过", "Skip"),
+    ("直接覆盖", "Overwrite"),
+    ("在文件名后添加数字", "Append a number to the file name"),
+    ("源文件比输出文件新时才覆盖", "Overwrite only when the source is newer than the output"),
+    ("日志文件", "LOG_FILE"),
+    ("语言", "LANG"),
+    ("日志格式", "FORMAT"),
+    ("配置文件", "CONFIG"),
+    ("名称", "NAME"),
+    ("输入文件/文件夹", "INPUT"),
+    ("输出文件夹", "OUTPUT"),
+    ("列表文件", "LIST_FILE"),
+    ("模式", "PATTERN"),
+    ("排除模式", "PATTERN"),
+    ("深度", "DEPTH"),
+    ("布局", "LAYOUT"),
+    ("策略", "POLICY"),
+    ("状态文件", "STATE_FILE"),
+    ("秒", "SECONDS"),
+    ("失败列表", "FAILED_LIST"),
+    ("报告文件", "REPORT_FILE"),
+    ("封面文件名", "COVER_NAME"),
+    ("模板", "TEMPLATE"),
+    ("分隔符", "SEPARATOR"),
+    ("像素", "PIXELS"),
+    ("质量", "QUALITY"),
+    ("图片", "IMAGE"),
+    // 日志
+    ("没有找到配置文件，无法使用配置方案[{}]", "No config file found, cannot use profile [{}]"),
+    ("无法读取配置文件[{}]: {}", "Cannot read config file [{}]: {}"),
+    ("配置文件格式错误[{}]: {}", "Invalid config file [{}]: {}"),
+    ("配置文件中没有配置方案[{}]", "Profile [{}] not found in the config file"),
+    ("配置文件中不能设置{}", "{} cannot be set in the config file"),
+    ("配置文件中有未知选项: {}", "Unknown option in the config file: {}"),
+    ("配置项{}的值无效", "Invalid value for config option {}"),
+    ("缩小封面: {}x{} -> 最大边长{}", "Scaling cover down: {}x{} -> max edge {}"),
+    ("封面大小: {} -> {} 字节", "Cover size: {} -> {} bytes"),
+    ("无法打开日志文件[{}]: {}", "Cannot open log file [{}]: {}"),
+    ("从[{}]中找到{}个失败的文件", "[{}]: found {} failed files"),
+    ("无法读取[{}]: {}", "Cannot read [{}]: {}"),
+    ("从[{}]中读取到{}个路径", "[{}]: read {} paths"),
+    ("根据已有的输出文件重建状态", "Rebuilding state from existing output files"),
+    ("试运行不会写入报告", "No report is written in a dry run"),
+    ("没有找到有效文件。使用-i参数输入需要解密的文件或文件夹。", "No valid files found. Use -i to pass the files or folders to decrypt."),
+    ("启用{}线程", "Using {} threads"),
+    ("共计用时{}秒", "took {} s in total"),
+    ("共计用时{}毫秒", "took {} ms in total"),
+    ("报告已保存到: {}", "Report saved to: {}"),
+    ("无法保存报告[{}]: {}", "Cannot save report [{}]: {}"),
+    ("失败的文件已记录到: {}，可以使用--retry重试", "Failed files were written to: {}, use --retry to try again"),
+    ("无法保存失败列表[{}]: {}", "Cannot save failed list [{}]: {}"),
+    ("成功解密{}个文件,{}个文件解密失败，{}", "Decrypted {} files, {} files failed, {}"),
+    ("新建{}个,覆盖{}个,重命名{}个,跳过{}个", "{} created, {} overwritten, {} renamed, {} skipped"),
+    ("{}个文件没有变化", "{} files unchanged"),
+    ("解密被中断，{}个文件没有处理", "Interrupted, {} files were not processed"),
+    ("无法监视文件夹: {}", "Cannot watch folders: {}"),
+    ("正在监视{}个文件夹，按Ctrl-C退出", "Watching {} folders, press Ctrl-C to quit"),
+    ("[{}]发现新文件", "[{}] New file found"),
+    ("[没有变化]", "[unchanged]"),
+    ("[新建]", "[create]"),
+    ("[覆盖]", "[overwrite]"),
+    ("[重命名]", "[rename]"),
+    ("[跳过]", "[skip]"),
+    ("[封面]", "[cover]"),
+    ("[失败]", "[failed]"),
+    ("计划新建{}个,覆盖{}个,重命名{}个,跳过{}个,{}个文件无法解析", "Plan: {} to create, {} to overwrite, {} to rename, {} to skip, {} files could not be parsed"),
+    ("[{}]源文件有变化", "[{}] Source file changed"),
+    ("[{}]解密失败: {}", "[{}] Decryption failed: {}"),
+    ("无法保存状态文件[{}]: {}", "Cannot save state file [{}]: {}"),
+    ("[{}]无法读取: {}", "[{}] Cannot read: {}"),
+    ("找到{}个已经转换的文件", "Found {} converted files"),
+    ("无法读取封面图片[{}]", "Cannot read cover image [{}]"),
+    ("没有找到有效文件。", "No valid files found."),
+    ("无法创建输出文件夹[{}]", "Cannot create output folder [{}]"),
+    ("[{}]封面已{}: {}", "[{}] Cover {}: {}"),
+    ("替换", "replaced"),
+    ("移除", "removed"),
+    ("[{}]封面处理失败: {}", "[{}] Cover processing failed: {}"),
+    ("成功处理{}个文件,{}个文件处理失败", "Processed {} files, {} files failed"),
+    ("标题", "Title"),
+    ("艺术家", "Artist"),
+    ("专辑", "Album"),
+    ("格式", "Format"),
+    ("比特率", "Bitrate"),
+    ("时长", "Duration"),
+    ("封面", "Cover"),
+    ("{} 字节 ({})", "{} bytes ({})"),
+    ("无", "none"),
+    ("{} 字节 (未知格式)", "{} bytes (unknown format)"),
+    ("歌曲ID", "Music ID"),
+    ("专辑ID", "Album ID"),
+    ("MV ID", "MV ID"),
+    ("[{}]读取信息失败: {}", "[{}] Cannot read information: {}"),
+    ("文件完整", "File is intact"),
+    ("[{}]文件损坏: {}", "[{}] File is corrupted: {}"),
+    ("{}个文件完整,{}个文件损坏", "{} files intact, {} files corrupted"),
+    ("开始解密[{}]文件", "Decrypting [{}]"),
+    ("[{}]输出文件已存在，跳过: {}", "[{}] Output file exists, skipped: {}"),
+    ("文件名：{}", "File name: {}"),
+    ("[{}]封面处理失败，使用原始封面: {}", "[{}] Cover processing failed, using the original cover: {}"),
+    ("文件路径: {}", "File path: {}"),
+    ("[{}] 文件已保存到: {}", "[{}] Saved to: {}"),
+    ("解密成功", "Decrypted"),
+    ("[{}]没有可以另存的封面", "[{}] No cover to save"),
+    ("封面已保存到: {}", "Cover saved to: {}"),
+    ("该文件不为NCM格式", "Not an NCM file"),
+    ("无法读取文件名称", "Cannot read the file name"),
+    ("无法读取歌曲元信息", "Cannot read the song metadata"),
+    ("封面无法保存", "Cannot save the cover"),
+    ("封面不是JPEG或PNG格式", "The cover is neither JPEG nor PNG"),
+    ("[{}]与本次运行中的其他文件输出到同一路径: {}", "[{}] maps to the same output as another file in this run: {}"),
+    ("[{}]缺少估算音乐数据大小需要的信息，无法检查文件是否完整", "[{}] lacks the information needed to estimate the music data size, cannot check whether it is complete"),
+    ("无法解密RC4密钥", "Cannot decrypt the RC4 key"),
+    ("无法识别解密后的音乐数据", "Unrecognized decrypted music data"),
+    ("文件不完整", "File is truncated"),
+    ("读取文件时发生错误", "Error while reading the file"),
+    ("写入文件时错误", "Error while writing the file"),
+    ("文件名不符合规范", "Invalid file name"),
+    ("未知错误", "Unknown error"),
+    ("空闲", "idle"),
+    ("完成{}/{}", "done {}/{}"),
+    ("{}，失败{}", "{}, {} failed"),
+    ("不支持的报告版本: {}", "Unsupported report version: {}"),
+    ("强制退出", "Forced exit"),
+    ("删除临时文件: {}", "Removing temporary file: {}"),
+    ("收到中断信号，正在等待解密中的文件完成。再按一次Ctrl-C强制退出", "Interrupted, waiting for the files being decrypted to finish. Press Ctrl-C again to force quit"),
+    ("无法处理中断信号: {}", "Cannot handle interrupt signals: {}"),
+    ("状态文件不存在: {}", "State file does not exist: {}"),
+    ("状态文件无法识别，将重新转换所有文件: {}", "Unrecognized state file, all files will be converted again: {}"),
+    ("模板中的括号没有闭合: {}", "Unclosed brace in template: {}"),
+    ("模板中有未知字段: {{{}}}", "Unknown field in template: {{{}}}"),
+    ("模板不能为空", "The template must not be empty"),
+    ("线程[{}]获得任务", "Worker [{}] got a job"),
+    ("线程[{}]结束任务", "Worker [{}] finished a job"),
+    ("将开启{}线程", "Starting {} threads"),
+    ("[{}]不是文件夹，不会被监视", "[{}] is not a folder and will not be watched"),
+    ("开始监视: {}", "Watching: {}"),
+    ("文件有变化: {}", "File changed: {}"),
+    ("文件没有变化，不再重复解密: {}", "File unchanged, not decrypting again: {}"),
+    ("监视文件夹时出错: {}", "Error while watching folders: {}"),
+    ("没有可以监视的文件夹", "No folders to watch"),
+];
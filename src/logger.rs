@@ -1,47 +1,206 @@
-use chrono;
+use crate::clap::LogFormat;
+use crate::progress;
 use env_logger::Builder;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
 
 pub struct Logger {}
 
+/// 当前线程正在处理的文件，写入JSON日志
+#[derive(Default)]
+struct Context {
+    /// 源文件的绝对路径
+    file: Option<String>,
+    /// 解密的阶段，见`ncmdump::Stage`
+    stage: Option<&'static str>,
+    /// 线程池中的线程编号，主线程为None
+    worker: Option<usize>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// 记录当前线程在线程池中的编号
+pub fn set_worker(id: usize) {
+    CONTEXT.with(|c| c.borrow_mut().worker = Some(id));
+}
+
+/// 当前线程在线程池中的编号
+pub fn worker() -> Option<usize> {
+    CONTEXT.with(|c| c.borrow().worker)
+}
+
+/// 记录当前线程开始或结束处理一个文件，同时清空阶段
+pub fn set_file(path: Option<&str>) {
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        c.file = path.map(str::to_string);
+        c.stage = None;
+    });
+}
+
+/// 记录当前线程的解密阶段
+pub fn set_stage(stage: Option<&'static str>) {
+    CONTEXT.with(|c| c.borrow_mut().stage = stage);
+}
+
+/// 输出日志时先擦掉进度条，输出完再画回来，同时写入日志文件
+struct ProgressLogger {
+    inner: env_logger::Logger,
+    /// 日志文件，不带颜色，时间包含日期
+    file: Option<Mutex<File>>,
+    format: LogFormat,
+}
+
+impl Log for ProgressLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        progress::suspend(|| self.inner.log(record));
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            let _ = match self.format {
+                LogFormat::Text => writeln!(
+                    file,
+                    "[{} {}] {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    level_name(record.level()),
+                    strip_ansi(&record.args().to_string())
+                ),
+                LogFormat::Json => writeln!(file, "{}", json_line(record)),
+            };
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
 impl Logger {
-    pub fn new() {
+    /// 初始化日志系统
+    ///
+    /// - verbosity 每个`-v`加一，每个`-q`减一。为0时使用RUST_LOG，没有设置时使用默认等级
+    /// - format 控制台和日志文件的格式
+    /// - log_file 同时写入的日志文件，追加写入
+    ///
+    /// 日志文件无法打开时仍然初始化控制台日志，并返回错误
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(verbosity: i8, format: LogFormat, log_file: Option<&Path>) -> std::io::Result<()> {
         let mut builder = Builder::new();
         if cfg!(debug_assertions) {
-            builder.filter(None, log::LevelFilter::Debug);
+            builder.filter(None, LevelFilter::Debug);
         } else {
-            builder.filter(None, log::LevelFilter::Info);
+            builder.filter(None, LevelFilter::Info);
         }
-        builder.format(move |buf, record| {
-            writeln!(
-                buf,
-                "[{} {}] {}",
-                format!("{}", chrono::Local::now().format("%H:%M:%S")),
-                match record.level() {
-                    log::Level::Error => {
-                        let style = buf.default_level_style(log::Level::Error);
-                        format!("{style}Error{style:#}")
-                    }
-                    log::Level::Warn => {
-                        let style = buf.default_level_style(log::Level::Warn);
-                        format!("{style}Warn{style:#}")
-                    }
-                    log::Level::Info => {
-                        let style = buf.default_level_style(log::Level::Info);
-                        format!("{style}Info{style:#}")
-                    }
-                    log::Level::Debug => {
-                        let style = buf.default_level_style(log::Level::Debug);
-                        format!("{style}Debug{style:#}")
-                    }
-                    log::Level::Trace => {
-                        let style = buf.default_level_style(log::Level::Trace);
-                        format!("{style}Trace{style:#}")
+        if let Ok(filters) = std::env::var("RUST_LOG") {
+            builder.parse_filters(&filters);
+        }
+        // 命令行参数优先
+        match verbosity {
+            0 => {}
+            ..=-2 => {
+                builder.filter(None, LevelFilter::Error);
+            }
+            -1 => {
+                builder.filter(None, LevelFilter::Warn);
+            }
+            1 => {
+                builder.filter(None, LevelFilter::Debug);
+            }
+            2.. => {
+                builder.filter(None, LevelFilter::Trace);
+            }
+        }
+        match format {
+            LogFormat::Text => builder.format(move |buf, record| {
+                let style = buf.default_level_style(record.level());
+                writeln!(
+                    buf,
+                    "[{} {style}{}{style:#}] {}",
+                    chrono::Local::now().format("%H:%M:%S"),
+                    level_name(record.level()),
+                    record.args(),
+                )
+            }),
+            LogFormat::Json => builder.format(|buf, record| writeln!(buf, "{}", json_line(record))),
+        };
+
+        let (file, result) = match log_file {
+            Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => (Some(Mutex::new(file)), Ok(())),
+                Err(e) => (None, Err(e)),
+            },
+            None => (None, Ok(())),
+        };
+        //初始化logger
+        let inner = builder.build();
+        log::set_max_level(inner.filter());
+        let _ = log::set_boxed_logger(Box::new(ProgressLogger {
+            inner,
+            file,
+            format,
+        }));
+        result
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "Error",
+        Level::Warn => "Warn",
+        Level::Info => "Info",
+        Level::Debug => "Debug",
+        Level::Trace => "Trace",
+    }
+}
+
+/// 一条JSON日志，字段为timestamp、level、message、file、stage、worker
+pub fn json_line(record: &Record) -> String {
+    CONTEXT.with(|c| {
+        let c = c.borrow();
+        serde_json::json!({
+            "timestamp": chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            "level": record.level().as_str().to_lowercase(),
+            "message": strip_ansi(&record.args().to_string()),
+            "file": c.file,
+            "stage": c.stage,
+            "worker": c.worker,
+        })
+        .to_string()
+    })
+}
+
+/// 去掉日志中的颜色控制符
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // 跳过 ESC [ ... 字母
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
                     }
-                },
-                record.args(),
-            )
-        });
-        builder.init(); //初始化logger
+                }
+            }
+            continue;
+        }
+        out.push(c);
     }
+    out
 }
@@ -1,28 +1,590 @@
-use std::{ops::Add, path::Path, sync::{Arc, Mutex}};
-use ::clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 #[allow(unused_imports)]
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
 use colored::Colorize;
 
-use walkdir::WalkDir; //遍历目录
-
+#[macro_use]
+mod i18n;
 mod clap;
+mod config;
+mod cover;
+mod discover;
+mod events;
+mod exitcode;
 mod logger;
 mod ncmdump;
+mod progress;
+mod report;
+mod signal;
+mod state;
+mod template;
 mod threadpool;
-use ncmdump::Ncmfile;
+mod watch;
+use discover::{collect_files, DiscoverOptions};
+use ncmdump::{Ncmfile, SaveAction};
 mod test;
 
-fn main() {
+fn main() -> ExitCode {
     let timer = ncmdump::TimeCompare::new();
+    let started_at = chrono::Local::now();
+    let cli = clap::Cli::parse_localized();
+
     // 初始化日志系统
-    logger::Logger::new();
+    let verbosity = cli.verbose.min(2) as i8 - cli.quiet.min(2) as i8;
+    if let Err(e) = logger::Logger::new(
+        verbosity,
+        cli.log_format,
+        cli.log_file.as_deref().map(Path::new),
+    ) {
+        error!("{}", tr!("无法打开日志文件[{}]: {}", cli.log_file.unwrap().yellow(), e));
+        return ExitCode::from(exitcode::INVALID_ARGS);
+    }
+
+    let args = match cli.command {
+        Some(clap::Commands::Cover {
+            input,
+            image,
+            remove: _,
+            output,
+        }) => {
+            return replace_cover(&input, image, output);
+        }
+        Some(clap::Commands::Info { input, json }) => {
+            return show_info(&input, json);
+        }
+        Some(clap::Commands::Check { input, workers }) => {
+            return check(&input, max_workers(workers));
+        }
+        Some(clap::Commands::Completions { shell }) => {
+            let mut command = clap::Cli::localized_command();
+            clap_complete::generate(shell, &mut command, "ncmmiao", &mut std::io::stdout());
+            return ExitCode::from(exitcode::SUCCESS);
+        }
+        Some(clap::Commands::Man) => {
+            return match clap_mangen::Man::new(clap::Cli::localized_command())
+                .render(&mut std::io::stdout())
+            {
+                Ok(_) => ExitCode::from(exitcode::SUCCESS),
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::from(exitcode::FAILED)
+                }
+            };
+        }
+        Some(clap::Commands::Dump(args)) => *args,
+        // 兼容以前的用法：ncmmiao -i x -o y
+        None => cli.dump,
+    };
+
+    // 中断时不再开始新的任务，并且仍然输出统计信息
+    signal::install();
+    if args.events {
+        events::enable();
+    }
+
+    let max_workers = max_workers(args.workers);
+
+    let mut input = args.input;
+    for list in &args.from_file {
+        match discover::read_list(list) {
+            Ok(paths) => {
+                info!("{}", tr!("从[{}]中读取到{}个路径", list.yellow(), paths.len()));
+                input.extend(paths);
+            }
+            Err(e) => {
+                error!("{}", tr!("无法读取[{}]: {}", list.yellow(), e));
+                return ExitCode::from(exitcode::INVALID_ARGS);
+            }
+        }
+    }
+
+    let mut discover = match DiscoverOptions::new(&args.include, &args.exclude) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(exitcode::INVALID_ARGS);
+        }
+    };
+    discover.max_depth = args.max_depth;
+    discover.skip_hidden = args.skip_hidden;
+    discover.follow_links = args.follow_links;
+
+    let outputdir = args.output.unwrap();
+
+    let template = match args.template {
+        Some(template) => match template::Template::parse(&template, &args.artist_separator) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::from(exitcode::INVALID_ARGS);
+            }
+        },
+        None => None,
+    };
+
+    let mut undumpfile = collect_files(&input, &discover); // 该列表将存入文件的路径
+    // 重试上次失败的文件
+    // 从失败列表重试时直接更新这个列表，否则写入输出文件夹
+    let mut failed_list = Path::new(&outputdir).join(report::FAILED_LIST_FILENAME);
+    if let Some(path) = &args.retry {
+        match report::load_failed(Path::new(path)) {
+            Ok(retry) => {
+                let files = match retry {
+                    report::Retry::Report(files) => files,
+                    report::Retry::List(files) => {
+                        failed_list = PathBuf::from(path);
+                        files
+                    }
+                };
+                info!("{}", tr!("从[{}]中找到{}个失败的文件", path.yellow(), files.len()));
+                undumpfile.extend(files);
+            }
+            Err(e) => {
+                error!("{}", tr!("无法读取[{}]: {}", path.yellow(), e));
+                return ExitCode::from(exitcode::INVALID_ARGS);
+            }
+        }
+    }
+    let undumpfile = discover::dedup(undumpfile);
+    let taskcount = undumpfile.len();
+
+    // 增量转换
+    let state_path = match (&args.state, args.incremental || args.rebuild_state) {
+        (Some(path), _) => Some(PathBuf::from(path)),
+        (None, true) => Some(Path::new(&outputdir).join(state::STATE_FILENAME)),
+        (None, false) => None,
+    };
+    let state = match &state_path {
+        Some(path) => state::State::load(path),
+        None => state::State::default(),
+    };
+
+    let options = ncmdump::DumpOptions {
+        cover_file: args.extract_cover,
+        cover: cover::CoverOptions {
+            max_size: args.cover_max_size,
+            quality: args.cover_quality,
+            jpeg: args.cover_jpeg,
+            strip: args.cover_strip,
+        },
+        template,
+        overwrite: args.overwrite,
+        ..Default::default()
+    };
+
+    let dumper = Dumper {
+        outputdir: PathBuf::from(&outputdir),
+        layout: args.layout,
+        options: Arc::new(options),
+        results: Arc::new(Mutex::new(Vec::new())),
+        state_path,
+        state: Arc::new(Mutex::new(state)),
+        // 第一次总是保存，重建的状态也需要写入
+        state_changed: Arc::new(AtomicBool::new(true)),
+        // 试运行只输出处理计划，不显示进度条
+        progress: if args.dry_run {
+            progress::Progress::hidden()
+        } else {
+            progress::Progress::new(max_workers)
+        },
+    };
+
+    if args.rebuild_state {
+        info!("{}", tr!("根据已有的输出文件重建状态"));
+        rebuild_state(
+            &mut dumper.state.lock().unwrap(),
+            &undumpfile,
+            &outputdir,
+            args.layout,
+            dumper.options.template.as_ref(),
+        );
+    }
+    let undumpfile: Vec<_> = undumpfile
+        .into_iter()
+        .filter(|file| dumper.needs_dump(file))
+        .collect();
+
+    if args.dry_run {
+        if args.report.is_some() {
+            warn!("{}", tr!("试运行不会写入报告"));
+        }
+        if taskcount == 0 {
+            error!("{}", tr!("没有找到有效文件。使用-i参数输入需要解密的文件或文件夹。"));
+            return ExitCode::from(exitcode::NOT_FOUND);
+        }
+        let totals = dry_run(&dumper, undumpfile);
+        return ExitCode::from(exitcode::summary(exitcode::SUCCESS, false, totals.failed));
+    }
+
+    let mut code = exitcode::SUCCESS;
+    if taskcount == 0 && !args.watch {
+        error!("{}", tr!("没有找到有效文件。使用-i参数输入需要解密的文件或文件夹。"));
+        code = exitcode::NOT_FOUND;
+    } else {
+        // 初始化线程池
+        let pool = threadpool::Pool::new(max_workers);
+        info!("{}", tr!("启用{}线程", max_workers));
+
+        for file in undumpfile {
+            if signal::interrupted() {
+                dumper.record(report::FileReport::new(&file.path, report::FileStatus::Cancelled));
+                continue;
+            }
+            dumper.submit(&pool, file);
+        }
+        if args.watch && !watch(&input, &discover, Duration::from_secs(args.watch_delay), &dumper, &pool) {
+            code = exitcode::INVALID_ARGS;
+        }
+    }
+    dumper.progress.finish();
+    dumper.save_state();
+    let timecount = timer.compare();
+    let showtime = || {
+        if timecount > 2000 {
+            tr!("共计用时{}秒", timecount / 1000)
+        } else {
+            tr!("共计用时{}毫秒", timecount)
+        }
+    };
+    let results = std::mem::take(&mut *dumper.results.lock().unwrap());
+    let report = report::Report::new(started_at, signal::interrupted(), results);
+    if let Some(path) = &args.report {
+        match report.save(Path::new(path)) {
+            Ok(_) => info!("{}", tr!("报告已保存到: {}", path.bright_cyan())),
+            Err(e) => error!("{}", tr!("无法保存报告[{}]: {}", path.yellow(), e)),
+        }
+    }
+    match report::save_failed_list(&failed_list, &report) {
+        Ok(0) => {}
+        Ok(_) => info!(
+            "{}",
+            tr!(
+                "失败的文件已记录到: {}，可以使用--retry重试",
+                failed_list.to_string_lossy().bright_cyan()
+            )
+        ),
+        Err(e) => error!(
+            "{}",
+            tr!("无法保存失败列表[{}]: {}", failed_list.to_string_lossy().yellow(), e)
+        ),
+    }
+    let summary = &report.totals;
+    info!(
+        "{}",
+        tr!(
+            "成功解密{}个文件,{}个文件解密失败，{}",
+            (summary.created + summary.overwritten + summary.renamed)
+                .to_string()
+                .bright_green(),
+            summary.failed.to_string().bright_red(),
+            showtime()
+        )
+    );
+    info!(
+        "{}",
+        tr!(
+            "新建{}个,覆盖{}个,重命名{}个,跳过{}个",
+            summary.created,
+            summary.overwritten,
+            summary.renamed,
+            summary.skipped
+        )
+    );
+    if dumper.state_path.is_some() {
+        info!("{}", tr!("{}个文件没有变化", summary.unchanged));
+    }
+    if signal::interrupted() {
+        warn!("{}", tr!("解密被中断，{}个文件没有处理", summary.cancelled));
+    }
+    let code = exitcode::summary(code, signal::interrupted(), summary.failed);
+    events::emit(events::Event::Summary {
+        interrupted: report.interrupted,
+        duration_ms: report.duration_ms,
+        exit_code: code,
+        totals: summary,
+    });
+    ExitCode::from(code)
+}
 
-    let cli = clap::Cli::parse();
+/// 监视输入文件夹，新文件写入完成后加入线程池
+///
+/// 无法监视时返回false
+fn watch(
+    input: &[String],
+    options: &DiscoverOptions,
+    delay: Duration,
+    dumper: &Dumper,
+    pool: &threadpool::Pool,
+) -> bool {
+    let mut watcher = match watch::Watcher::new(input, options.clone(), delay) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("{}", tr!("无法监视文件夹: {}", e));
+            return false;
+        }
+    };
+    info!("{}", tr!("正在监视{}个文件夹，按Ctrl-C退出", watcher.dir_count()));
+    while !signal::interrupted() {
+        for file in watcher.poll() {
+            debug!("{}", tr!("[{}]发现新文件", file.path.yellow()));
+            if dumper.needs_dump(&file) {
+                dumper.submit(pool, file);
+            }
+        }
+        dumper.save_state();
+    }
+    true
+}
 
-    // 最大线程数
-    let max_workers = match cli.workers {
+/// 只输出每个文件的处理计划，不创建任何文件夹或文件
+///
+/// 返回计划中各种处理方式的数量，无法解析的文件计入`failed`
+fn dry_run(dumper: &Dumper, files: Vec<discover::InputFile>) -> report::Totals {
+    let unchanged = dumper.results.lock().unwrap().len();
+    let mut totals = report::Totals {
+        discovered: unchanged + files.len(),
+        unchanged,
+        ..Default::default()
+    };
+    for report in dumper.results.lock().unwrap().iter() {
+        println!("{} {}", tr!("[没有变化]").dimmed(), report.path);
+    }
+    for file in files {
+        let output = file.output_dir(&dumper.outputdir, dumper.layout);
+        match Ncmfile::new(&file.path).and_then(|mut n| n.plan(&output, &dumper.options)) {
+            Ok(result) => {
+                let label = match result.action {
+                    SaveAction::Created => tr!("[新建]").bright_green(),
+                    SaveAction::Overwritten => tr!("[覆盖]").bright_yellow(),
+                    SaveAction::Renamed => tr!("[重命名]").bright_cyan(),
+                    SaveAction::Skipped => tr!("[跳过]").dimmed(),
+                };
+                match result.action {
+                    SaveAction::Created => totals.created += 1,
+                    SaveAction::Overwritten => totals.overwritten += 1,
+                    SaveAction::Renamed => totals.renamed += 1,
+                    SaveAction::Skipped => totals.skipped += 1,
+                }
+                println!("{} {} → {}", label, file.path, result.path.to_string_lossy());
+                if let Some(cover_path) = &result.cover_path {
+                    println!(
+                        "  {} {}",
+                        tr!("[封面]").dimmed(),
+                        cover_path.to_string_lossy()
+                    );
+                }
+            }
+            Err(e) => {
+                totals.failed += 1;
+                println!("{} {}: {}", tr!("[失败]").bright_red(), file.path, e);
+            }
+        }
+    }
+    info!(
+        "{}",
+        tr!(
+            "计划新建{}个,覆盖{}个,重命名{}个,跳过{}个,{}个文件无法解析",
+            totals.created,
+            totals.overwritten,
+            totals.renamed,
+            totals.skipped,
+            totals.failed
+        )
+    );
+    if dumper.state_path.is_some() {
+        info!("{}", tr!("{}个文件没有变化", totals.unchanged));
+    }
+    totals
+}
+
+/// 解密任务共享的数据，批量解密和监视模式共用
+struct Dumper {
+    outputdir: PathBuf,
+    layout: clap::Layout,
+    options: Arc<ncmdump::DumpOptions>,
+    /// 每个文件的处理结果
+    results: Arc<Mutex<Vec<report::FileReport>>>,
+    /// 增量转换的状态文件，为None时不使用增量转换
+    state_path: Option<PathBuf>,
+    state: Arc<Mutex<state::State>>,
+    /// 状态是否有还没有保存的改动
+    state_changed: Arc<AtomicBool>,
+    progress: Arc<progress::Progress>,
+}
+
+impl Dumper {
+    /// 增量转换时检查源文件的状态，没有变化的文件不需要再解密
+    fn needs_dump(&self, file: &discover::InputFile) -> bool {
+        if self.state_path.is_none() {
+            return true;
+        }
+        match self.state.lock().unwrap().status(&file.path) {
+            state::Status::Unchanged => {
+                self.record(report::FileReport::new(&file.path, report::FileStatus::Unchanged));
+                false
+            }
+            state::Status::Changed(entry) => {
+                debug!("{}", tr!("[{}]源文件有变化", file.path.yellow()));
+                self.options
+                    .redo_outputs
+                    .lock()
+                    .unwrap()
+                    .insert(PathBuf::from(entry.output));
+                true
+            }
+            state::Status::New => true,
+        }
+    }
+
+    /// 将一个文件加入线程池
+    fn submit(&self, pool: &threadpool::Pool, file: discover::InputFile) {
+        let output = file.output_dir(&self.outputdir, self.layout);
+        let filepath = file.path;
+        let options = Arc::clone(&self.options);
+        let results = Arc::clone(&self.results);
+        let state = Arc::clone(&self.state);
+        let state_changed = Arc::clone(&self.state_changed);
+        let progress = Arc::clone(&self.progress);
+        let size = fs::metadata(&filepath).map(|m| m.len()).unwrap_or(0);
+        progress.add_file(size);
+        events::emit(events::Event::Discovered {
+            path: &report::absolute(Path::new(&filepath)),
+            bytes: size,
+        });
+        pool.execute(move || {
+            // 收到中断信号后，已经在队列中的任务也不再执行
+            if signal::interrupted() {
+                let report = report::FileReport::new(&filepath, report::FileStatus::Cancelled);
+                events::file_done(&report);
+                results.lock().unwrap().push(report);
+                return;
+            }
+            let timer = Instant::now();
+            let path = report::absolute(Path::new(&filepath));
+            logger::set_file(Some(&path));
+            events::emit(events::Event::Started {
+                path: &path,
+                worker: logger::worker(),
+            });
+            let name = Path::new(&filepath).file_name().unwrap_or_default().to_string_lossy();
+            let file_progress = progress.start(&name, size);
+            let result = Ncmfile::new(filepath.as_str()).and_then(|mut n| {
+                let mut hook = file_progress.hook();
+                let mut bytes_progress = events::BytesProgress::new(&path, size);
+                n.on_progress(move |bytes| {
+                    hook(bytes);
+                    bytes_progress.add(bytes);
+                });
+                let stage_path = path.clone();
+                n.on_stage(move |stage| {
+                    events::emit(events::Event::Stage {
+                        path: &stage_path,
+                        stage,
+                    })
+                });
+                n.dump(&output, &options)
+            });
+            file_progress.finish(result.is_ok());
+            let mut report = report::FileReport::new(&filepath, report::FileStatus::Failed);
+            report.output_dir = Some(report::absolute(&output));
+            match result {
+                Ok(result) => {
+                    report.status = result.action.into();
+                    // 跳过的文件不是本次生成的，不记录到状态中
+                    if result.action != SaveAction::Skipped {
+                        if let Some(entry) = state::Entry::new(&filepath, &result.path) {
+                            state.lock().unwrap().insert(&filepath, entry);
+                            state_changed.store(true, Ordering::Relaxed);
+                        }
+                        report.bytes_out = fs::metadata(&result.path).map(|m| m.len()).unwrap_or(0);
+                    }
+                    report.output = Some(report::absolute(&result.path));
+                    report.format = Some(result.format);
+                    report.metadata = Some((&result.metadata).into());
+                }
+                Err(e) => {
+                    error!("{}", tr!("[{}]解密失败: {}", filepath.yellow(), e));
+                    report.error = Some((&e).into());
+                }
+            }
+            report.duration_ms = timer.elapsed().as_millis() as u64;
+            events::file_done(&report);
+            results.lock().unwrap().push(report);
+            logger::set_file(None);
+        });
+    }
+
+    /// 记录没有经过解密的文件
+    fn record(&self, report: report::FileReport) {
+        events::emit(events::Event::Discovered {
+            path: &report.path,
+            bytes: report.bytes_in,
+        });
+        events::file_done(&report);
+        self.results.lock().unwrap().push(report);
+    }
+
+    /// 有改动时保存状态文件
+    fn save_state(&self) {
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return,
+        };
+        if !self.state_changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = self.state.lock().unwrap().save(path) {
+            error!("{}", tr!("无法保存状态文件[{}]: {}", path.to_string_lossy().yellow(), e));
+        }
+    }
+}
+
+/// 根据已经存在的输出文件重建状态
+///
+/// 根据文件头部计算输出路径，输出文件存在时记录下来。元信息中没有格式时需要解密音乐数据
+fn rebuild_state(
+    state: &mut state::State,
+    files: &[discover::InputFile],
+    outputdir: &str,
+    layout: clap::Layout,
+    template: Option<&template::Template>,
+) {
+    state.clear();
+    let options = ncmdump::DumpOptions {
+        template: template.cloned(),
+        ..Default::default()
+    };
+    let mut count = 0;
+    for file in files {
+        let output = file.output_dir(Path::new(outputdir), layout);
+        let path = Ncmfile::new(&file.path).and_then(|mut n| n.target_path(&output, &options));
+        match path {
+            Ok(path) if path.exists() => {
+                if let Some(entry) = state::Entry::new(&file.path, &path) {
+                    state.insert(&file.path, entry);
+                    count += 1;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("{}", tr!("[{}]无法读取: {}", file.path.yellow(), e)),
+        }
+    }
+    info!("{}", tr!("找到{}个已经转换的文件", count));
+}
+
+/// 最大线程数
+fn max_workers(workers: Option<usize>) -> usize {
+    match workers {
         Some(n) => {
             if n >= 1 {
                 n
@@ -31,84 +593,188 @@ fn main() {
             }
         }
         None => 4,
-    };
+    }
+}
 
-    let input = cli.input;
+/// 子命令的输入文件，不使用解密时的匹配规则
+fn input_paths(input: &[String]) -> Vec<String> {
+    collect_files(input, &DiscoverOptions::default())
+        .into_iter()
+        .map(|file| file.path)
+        .collect()
+}
 
-    let outputdir = cli.output.unwrap();
+/// 替换或移除ncm文件的封面
+fn replace_cover(input: &[String], image: Option<String>, output: Option<String>) -> ExitCode {
+    let image_data = match image {
+        Some(image) => match fs::read(&image) {
+            Ok(data) => Some(data),
+            Err(_) => {
+                error!("{}", tr!("无法读取封面图片[{}]", image.yellow()));
+                return ExitCode::from(exitcode::INVALID_ARGS);
+            }
+        },
+        None => None,
+    };
 
-    let mut undumpfile = Vec::new(); // 该列表将存入文件的路径
+    let files = input_paths(input);
+    if files.is_empty() {
+        error!("{}", tr!("没有找到有效文件。"));
+        return ExitCode::from(exitcode::NOT_FOUND);
+    }
+    if let Some(output) = &output {
+        if fs::create_dir_all(output).is_err() {
+            error!("{}", tr!("无法创建输出文件夹[{}]", output.yellow()));
+            return ExitCode::from(exitcode::INVALID_ARGS);
+        }
+    }
 
-    for arg in input {
-        //解析传入的每一个路径：文件or文件夹
-        let path = Path::new(&arg);
+    let mut successful = 0;
+    for filepath in &files {
+        let result = Ncmfile::new(filepath).and_then(|mut n| {
+            let target = match &output {
+                Some(output) => Path::new(output).join(&n.fullfilename),
+                None => Path::new(filepath).to_path_buf(),
+            };
+            n.replace_cover(image_data.as_deref(), &target)
+                .map(|_| target)
+        });
+        match result {
+            Ok(target) => {
+                successful += 1;
+                info!(
+                    "{}",
+                    tr!(
+                        "[{}]封面已{}: {}",
+                        filepath.yellow(),
+                        if image_data.is_some() { tr!("替换") } else { tr!("移除") },
+                        target.to_str().unwrap().bright_cyan()
+                    )
+                );
+            }
+            Err(e) => error!("{}", tr!("[{}]封面处理失败: {}", filepath.yellow(), e)),
+        }
+    }
+    info!(
+        "{}",
+        tr!(
+            "成功处理{}个文件,{}个文件处理失败",
+            successful.to_string().bright_green(),
+            (files.len() - successful).to_string().bright_red()
+        )
+    );
+    ExitCode::from(if successful < files.len() {
+        exitcode::FAILED
+    } else {
+        exitcode::SUCCESS
+    })
+}
 
-        if path.is_file() {
-            // 当后缀符合为ncm时才加入列表
-            match path.extension() {
-                Some(extension) => {
-                    if extension == "ncm" {
-                        let _ = &mut undumpfile.push(arg.to_owned());
-                    }
-                }
-                None => {}
-            }
-        } else if path.is_dir() {
-            for entry in WalkDir::new(path) {
-                let new_entry = entry.unwrap().clone();
-                let filepath = new_entry.into_path();
-                // 当后缀符合为ncm时才加入列表
-                match filepath.extension() {
-                    Some(extension) => {
-                        if extension == "ncm" {
-                            let _ = &mut undumpfile.push(String::from(filepath.to_str().unwrap()));
-                        }
-                    }
-                    None => {
-                        continue;
-                    }
-                }
+/// 输出ncm文件的歌曲信息
+fn show_info(input: &[String], json: bool) -> ExitCode {
+    let files = input_paths(input);
+    if files.is_empty() {
+        error!("{}", tr!("没有找到有效文件。"));
+        return ExitCode::from(exitcode::NOT_FOUND);
+    }
+
+    let mut entries = Vec::new();
+    let mut failed = false;
+    for filepath in &files {
+        let info = Ncmfile::new(filepath).and_then(|mut n| n.info());
+        failed |= info.is_err();
+        if json {
+            let mut entry = match &info {
+                Ok(info) => serde_json::to_value(info).unwrap(),
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            };
+            entry["file"] = serde_json::Value::from(filepath.as_str());
+            entries.push(entry);
+            continue;
+        }
+        match info {
+            Ok(info) => {
+                // 中文占两个字符宽度，手动对齐
+                let row = |label: &str, value: String| {
+                    let width: usize = label.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum();
+                    println!("  {}{} {}", label, " ".repeat(10usize.saturating_sub(width)), value);
+                };
+                let metadata = info.metadata;
+                println!("{}", filepath.yellow());
+                row(tr!("标题"), metadata.music_name);
+                row(tr!("艺术家"), metadata.music_artist.join(" / "));
+                row(tr!("专辑"), metadata.album);
+                row(tr!("格式"), metadata.format);
+                row(tr!("比特率"), format!("{} kbps", metadata.bitrate / 1000));
+                row(
+                    tr!("时长"),
+                    format!("{:02}:{:02}", metadata.duration / 60000, metadata.duration / 1000 % 60),
+                );
+                row(
+                    tr!("封面"),
+                    match info.cover_type {
+                        Some(t) => tr!("{} 字节 ({})", info.cover_size, t),
+                        None if info.cover_size == 0 => tr!("无").to_string(),
+                        None => tr!("{} 字节 (未知格式)", info.cover_size),
+                    },
+                );
+                row(tr!("歌曲ID"), metadata.music_id);
+                row(tr!("专辑ID"), metadata.album_id);
+                row(tr!("MV ID"), metadata.mv_id);
             }
+            Err(e) => error!("{}", tr!("[{}]读取信息失败: {}", filepath.yellow(), e)),
         }
     }
-    let taskcount = undumpfile.len();
-    let successful = Arc::new(Mutex::new(0));
-    if taskcount == 0 {
-        error!("没有找到有效文件。使用-i参数输入需要解密的文件或文件夹。")
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+    }
+    ExitCode::from(if failed {
+        exitcode::FAILED
     } else {
-        // 初始化线程池
-        let pool = threadpool::Pool::new(max_workers);
-        info!("启用{}线程", max_workers);
+        exitcode::SUCCESS
+    })
+}
 
-        for filepath in undumpfile {
-            let output = outputdir.clone();
+/// 校验ncm文件，有文件损坏时以非零状态码退出
+fn check(input: &[String], max_workers: usize) -> ExitCode {
+    let files = input_paths(input);
+    let taskcount = files.len();
+    if taskcount == 0 {
+        error!("{}", tr!("没有找到有效文件。"));
+        return ExitCode::from(exitcode::NOT_FOUND);
+    }
+
+    let successful = Arc::new(Mutex::new(0));
+    {
+        let pool = threadpool::Pool::new(max_workers);
+        for filepath in files {
             let successful = Arc::clone(&successful);
             pool.execute(move || {
-                match Ncmfile::new(filepath.as_str()) {
-                    Ok(mut n) => match n.dump(Path::new(&output)) {
-                        Ok(_) => {
-                            let mut num = successful.lock().unwrap();
-                            *num += 1;},
-                        Err(e) => error!("[{}]解密失败: {}", filepath.yellow(), e),
-                    },
-                    Err(e) => error!("[{}]解密失败: {}", filepath.yellow(), e),
+                logger::set_file(Some(&report::absolute(Path::new(&filepath))));
+                match Ncmfile::new(&filepath).and_then(|mut n| n.check()) {
+                    Ok(_) => {
+                        *successful.lock().unwrap() += 1;
+                        info!("[{}]{}", filepath.yellow(), tr!("文件完整").bright_green());
+                    }
+                    Err(e) => error!("{}", tr!("[{}]文件损坏: {}", filepath.yellow(), e)),
                 }
+                logger::set_file(None);
             });
         }
     }
-    let timecount = timer.compare();
-    let showtime = || {
-        if timecount > 2000 {
-            format!("共计用时{}秒", timecount / 1000)
-        } else {
-            format!("共计用时{}毫秒", timecount)
-        }
-    };
+
     let successful = *successful.lock().unwrap();
     info!(
-        "成功解密{}个文件,{}个文件解密失败，{}",
-        successful.to_string().bright_green(),
-        (taskcount - successful).to_string().bright_red(),
-        showtime()
-    )
+        "{}",
+        tr!(
+            "{}个文件完整,{}个文件损坏",
+            successful.to_string().bright_green(),
+            (taskcount - successful).to_string().bright_red()
+        )
+    );
+    ExitCode::from(if successful < taskcount {
+        exitcode::FAILED
+    } else {
+        exitcode::SUCCESS
+    })
 }
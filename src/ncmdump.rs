@@ -1,6 +1,11 @@
 use aes::cipher::generic_array::typenum::U16;
 use aes::cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit};
 use aes::Aes128;
+use crate::cover::{self, CoverOptions};
+use crate::clap::OverwritePolicy;
+use crate::logger;
+use crate::signal;
+use crate::template::Template;
 use audiotags::{MimeType, Picture, Tag};
 use base64::{self, Engine};
 use colored::*;
@@ -8,12 +13,15 @@ use hex::decode;
 use lazy_static::lazy_static;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use serde_derive::{Deserialize, Serialize};
+use serde_derive::Serialize;
 use serde_json::{self, Value};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::vec;
 
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -35,9 +43,25 @@ pub struct Ncmfile {
     pub fullfilename: String,
     /// 文件大小
     pub size: u64,
+    /// 源文件的绝对路径，用来判断两个任务是否来自同一个源文件
+    pub source: PathBuf,
     /// 游标
     pub position: u64,
+    /// 解密进度的回调
+    progress: Option<Hook<u64>>,
+    /// 进入新的解密阶段时的回调
+    stage: Option<Hook<Stage>>,
 }
+
+/// 解密过程中的回调
+pub struct Hook<T>(Box<dyn FnMut(T) + Send>);
+
+impl<T> std::fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
 impl Ncmfile {
     pub fn new(filepath: &str) -> Result<Ncmfile, NcmError> {
         let file = match File::open(filepath) {
@@ -47,6 +71,7 @@ impl Ncmfile {
         let path = Path::new(filepath);
         let fullfilename = path.file_name().unwrap().to_str().unwrap().to_string();
         let size = file.metadata().unwrap().len();
+        let source = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
         let filename = match Path::new(&filepath).file_stem() {
             Some(f) => f.to_str().unwrap().to_string(),
             None => return Err(NcmError::CannotReadFileName),
@@ -56,16 +81,35 @@ impl Ncmfile {
             filename,
             fullfilename,
             size,
+            source,
             position: 0,
+            progress: None,
+            stage: None,
         })
     }
+
+    /// 设置解密进度的回调，每解密一块音乐数据调用一次
+    pub fn on_progress<F>(&mut self, f: F)
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.progress = Some(Hook(Box::new(f)));
+    }
+
+    /// 设置进入新的解密阶段时的回调
+    pub fn on_stage<F>(&mut self, f: F)
+    where
+        F: FnMut(Stage) + Send + 'static,
+    {
+        self.stage = Some(Hook(Box::new(f)));
+    }
     /// 根据传入的长度来读取文件
     ///
     /// 该函数可以记录上次读取的位置，下次读取时从上次读取的位置开始
     /// - length 想要读取的长度
     pub fn seekread(&mut self, length: u64) -> Result<Vec<u8>, NcmError> {
         if self.position + length > self.size {
-            return Err(NcmError::FileReadError);
+            Err(NcmError::FileReadError)
         } else {
             let mut reader = BufReader::new(&self.file);
             let _ = reader.seek(SeekFrom::Start(self.position));
@@ -84,7 +128,7 @@ impl Ncmfile {
     #[allow(dead_code)]
     pub fn seekread_from(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, NcmError> {
         if self.position + length > self.size {
-            return Err(NcmError::FileReadError);
+            Err(NcmError::FileReadError)
         } else {
             let mut reader = BufReader::new(&self.file);
             let _ = reader.seek(SeekFrom::Start(offset));
@@ -106,7 +150,7 @@ impl Ncmfile {
     pub fn seekread_no_error(&mut self, length: u64) -> Vec<u8> {
         if self.position + length > self.size {
             if self.position >= self.size {
-                return vec![];
+                vec![]
             } else {
                 let mut reader = BufReader::new(&self.file);
                 let _ = reader.seek(SeekFrom::Start(self.position));
@@ -114,7 +158,7 @@ impl Ncmfile {
                 let mut buf: Vec<u8> = vec![0; (self.size - self.position) as usize];
                 let _ = reader.read_exact(&mut buf);
                 self.position += length;
-                return buf[..].to_vec();
+                buf[..].to_vec()
             }
         } else {
             let mut reader = BufReader::new(&self.file);
@@ -128,7 +172,7 @@ impl Ncmfile {
     /// 跳过某些数据
     pub fn skip(&mut self, length: u64) -> Result<(), NcmError> {
         if self.position + length > self.size {
-            return Err(NcmError::FileReadError);
+            Err(NcmError::FileReadError)
         } else {
             self.position += length;
             Ok(())
@@ -136,16 +180,17 @@ impl Ncmfile {
     }
     ///按字节进行0x64异或。
     fn parse_key(key: &mut [u8]) -> &[u8] {
-        for i in 0..key.len() {
-            key[i] ^= 0x64;
+        for byte in key.iter_mut() {
+            *byte ^= 0x64;
         }
         key
     }
 
-    /// 解密函数
-    #[allow(unused_assignments)]
-    pub fn dump(&mut self, outputdir: &Path) -> Result<(), NcmError> {
-        info!("开始解密[{}]文件", self.fullfilename.yellow());
+    /// 解析文件头部：密钥、元信息、封面
+    ///
+    /// 不会解密音乐数据。解析完成后游标位于音乐数据的开头
+    pub fn read_header(&mut self) -> Result<NcmHeader, NcmError> {
+        self.position = 0;
         // 获取magic header 。应为CTENFDAM
         let magic_header = match self.seekread(8) {
             Ok(header) => header,
@@ -172,72 +217,167 @@ impl Ncmfile {
 
         trace!("获取RC4密钥长度");
         //小端模式读取RC4密钥长度 正常情况下应为128
-        let key_length = u32::from_le_bytes(self.seekread(4).unwrap().try_into().unwrap()) as u64;
+        let key_length = self.read_u32()? as u64;
         // debug!("RC4密钥长度为：{}", key_length);
 
         //读取密钥 开头应为 neteasecloudmusic
         trace!("读取RC4密钥");
-        let mut key_data = self.seekread(key_length).unwrap();
+        let mut key_data = self.seekread(key_length)?;
+        if key_data.is_empty() || !key_data.len().is_multiple_of(16) {
+            return Err(NcmError::KeyError);
+        }
         //aes128解密
         let key_data = &aes128_to_slice(&KEY_CORE, Self::parse_key(&mut key_data[..])); //先把密钥按照字节进行0x64异或
                                                                                         // RC4密钥
-        let key_data = unpad(&key_data[..])[17..].to_vec(); //去掉neteasecloudmusic
+        let key_data = match unpad(&key_data[..]) {
+            Some(k) if k.len() > 17 && k.starts_with(b"neteasecloudmusic") => k[17..].to_vec(), //去掉neteasecloudmusic
+            _ => return Err(NcmError::KeyError),
+        };
 
         //读取meta信息的数据大小
         trace!("获取meta信息数据大小");
-        let meta_length = u32::from_le_bytes(self.seekread(4)?.try_into().unwrap()) as u64;
+        let meta_length = self.read_u32()? as u64;
 
         // 读取meta信息
         trace!("读取meta信息");
         let meta_data = {
             let mut meta_data = self.seekread(meta_length)?; //读取源数据
-                                                             //字节对0x63进行异或。
-            for i in 0..meta_data.len() {
-                meta_data[i] ^= 0x63;
+            if meta_data.len() < 22 {
+                return Err(NcmError::CannotReadMetaInfo);
+            }
+            //字节对0x63进行异或。
+            for byte in meta_data.iter_mut() {
+                *byte ^= 0x63;
             }
             // base64解密
             let mut decode_data = Vec::<u8>::new();
-            let _ = match &base64::engine::general_purpose::STANDARD
+            if base64::engine::general_purpose::STANDARD
                 .decode_vec(&mut meta_data[22..], &mut decode_data)
+                .is_err()
             {
-                Err(_) => return Err(NcmError::CannotReadMetaInfo),
-                _ => (),
-            };
+                return Err(NcmError::CannotReadMetaInfo);
+            }
+            if decode_data.is_empty() || !decode_data.len().is_multiple_of(16) {
+                return Err(NcmError::CannotReadMetaInfo);
+            }
             // aes128解密
             let aes_data = aes128_to_slice(&KEY_META, &decode_data);
-            // unpadding
-            let json_data = match String::from_utf8(unpad(&aes_data)[6..].to_vec()) {
-                Ok(o) => o,
-                Err(_) => return Err(NcmError::CannotReadMetaInfo),
+            // unpadding，去掉开头的music:
+            let json_data = match unpad(&aes_data) {
+                Some(d) if d.len() > 6 => match String::from_utf8(d[6..].to_vec()) {
+                    Ok(o) => o,
+                    Err(_) => return Err(NcmError::CannotReadMetaInfo),
+                },
+                _ => return Err(NcmError::CannotReadMetaInfo),
             };
             debug!("json_data: {}", json_data);
-            let data: Value = match serde_json::from_str(&json_data[..]){Ok(o) => o,
+            let data: Value = match serde_json::from_str(&json_data[..]) {
+                Ok(o) => o,
                 Err(_) => return Err(NcmError::CannotReadMetaInfo),
             }; //解析json数据
             data
         };
 
-        // 跳过4个字节的校验码
+        // 封面区块从校验码开始
+        let cover_offset = self.position;
+
+        // 读取4个字节的封面帧校验码，解密时不使用
         trace!("读取校验码");
-        // let _crc32 = u32::from_le_bytes(self.seekread(4).unwrap().try_into().unwrap()) as u64;
-        self.skip(4)?;
+        let crc32 = self.read_u32()?;
+
+        // 1个字节用途不明
+        let cover_flag = self.seekread(1)?[0];
 
-        // 跳过5个字节
-        trace!("跳过5个字节");
-        self.skip(5)?;
+        // 封面帧的长度，图片之后可能还有填充
+        trace!("获取封面帧长度");
+        let cover_frame_length = self.read_u32()?;
 
         // 获取图片数据的大小
         trace!("获取图片数据的大小");
-        let image_data_length =
-            u32::from_le_bytes(self.seekread(4)?.try_into().unwrap()) as u64;
+        let image_data_length = self.read_u32()? as u64;
 
-        // 读取图片，并写入文件当中
+        // 读取图片
         let image_data = self.seekread(image_data_length)?; //读取图片数据
 
+        // 跳过图片之后的填充，封面帧长度小于图片大小时没有填充
+        self.skip((cover_frame_length as u64).saturating_sub(image_data_length))?;
+
+        Ok(NcmHeader {
+            key_data,
+            meta: meta_data,
+            cover_offset,
+            crc32,
+            cover_flag,
+            cover_frame_length,
+            image_data,
+            music_offset: self.position,
+        })
+    }
+
+    /// 读取歌曲信息，不解密音乐数据
+    pub fn info(&mut self) -> Result<NcmInfo, NcmError> {
+        let header = self.read_header()?;
+        Ok(NcmInfo {
+            metadata: Metadata::from_value(&header.meta),
+            cover_size: header.image_data.len(),
+            cover_type: cover_mime(&header.image_data).map(String::from),
+        })
+    }
+
+    /// 校验文件，解析文件头并解密音乐数据但不写入文件
+    ///
+    /// 封面帧的CRC32校验码不在检查范围内
+    pub fn check(&mut self) -> Result<(), NcmError> {
+        // 文件头、密钥与元信息
+        let header = self.read_header()?;
+        let metadata = Metadata::from_value(&header.meta);
+
+        let music_data = self.decrypt_music(&header.key_data);
+
+        // 音乐数据的文件头需要与元信息中的格式一致
+        trace!("校验音乐数据");
+        let format = match music_format(&music_data) {
+            Some(format) if metadata.format.is_empty() || metadata.format == format => format,
+            _ => return Err(NcmError::MusicDataError),
+        };
+
+        // 音乐数据小于最少需要的大小时视为文件不完整
+        // FLAC的比特率不固定，根据STREAMINFO计算；MP3根据元信息中的比特率和时长估算
+        let minimum = match format {
+            "flac" => flac_min_size(&music_data),
+            _ if metadata.bitrate == 0 || metadata.duration == 0 => None,
+            _ => Some(metadata.bitrate.saturating_mul(metadata.duration) / 8000 / 5 * 4),
+        };
+        match minimum {
+            Some(minimum) if (music_data.len() as u64) < minimum => Err(NcmError::FileTruncated),
+            Some(_) => Ok(()),
+            None => {
+                debug!(
+                    "{}",
+                    tr!(
+                        "[{}]缺少估算音乐数据大小需要的信息，无法检查文件是否完整",
+                        self.fullfilename.yellow()
+                    )
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// 小端模式读取4个字节
+    fn read_u32(&mut self) -> Result<u32, NcmError> {
+        Ok(u32::from_le_bytes(self.seekread(4)?.try_into().unwrap()))
+    }
+
+    /// 解密音乐数据
+    ///
+    /// 从游标位置一直读取到文件末尾，调用前需要先读取文件头部
+    /// - key_data RC4密钥
+    #[allow(unused_assignments)]
+    pub fn decrypt_music(&mut self, key_data: &[u8]) -> Vec<u8> {
         trace!("组成密码盒");
         let key_box = {
             let key_length = key_data.len();
-            let key_data = Vec::from(key_data);
             let mut key_box = (0..=255).collect::<Vec<u8>>();
             let mut temp = 0;
             let mut last_byte = 0;
@@ -245,7 +385,7 @@ impl Ncmfile {
 
             for i in 0..=255 {
                 let swap = key_box[i as usize] as u64;
-                temp = (swap + last_byte as u64 + key_data[key_offset as usize] as u64) & 0xFF;
+                temp = (swap + last_byte + key_data[key_offset] as u64) & 0xFF;
                 key_offset += 1;
                 if key_offset >= key_length {
                     key_offset = 0;
@@ -278,7 +418,6 @@ impl Ncmfile {
 
         // let key_box = key_box[0..(key_box.len()-key_box[key_box.len() as usize-1] as usize)].to_vec();
 
-        //解密音乐数据
         trace!("解密音乐数据");
         let mut music_data: Vec<u8> = Vec::new();
         loop {
@@ -290,12 +429,15 @@ impl Ncmfile {
                     let j = i & 0xFF;
 
                     chunk[i - 1] ^= key_box[(key_box[j] as usize
-                        + key_box[(key_box[j as usize] as usize + j) & 0xff] as usize)
+                        + key_box[(key_box[j] as usize + j) & 0xff] as usize)
                         & 0xff]
                     // chunk[i - 1] ^= key_box[(key_box[j] + key_box[(key_box[j as usize] as usize + j as usize) & 0xFF]) & 0xFF];
                 }
                 //向music_data中最追加chunk
                 music_data.append(&mut chunk);
+                if let Some(Hook(hook)) = &mut self.progress {
+                    hook(chunk_length as u64);
+                }
             } else {
                 break;
             }
@@ -323,115 +465,515 @@ impl Ncmfile {
         // debug!("music_data：{:?}", music_data);
         // debug!("长度：{}", stream.len());
 
-        //退出循环，写入文件
+        music_data
+    }
 
-        //处理文件路径
-        trace!("拼接文件路径");
-        let path = {
-            let filename = format!(
-                "{}.{}",
-                self.filename,
-                meta_data.get("format").unwrap().as_str().unwrap()
+    /// 解密函数
+    ///
+    /// 返回输出文件的路径以及处理方式
+    pub fn dump(&mut self, outputdir: &Path, options: &DumpOptions) -> Result<DumpResult, NcmError> {
+        self.enter(Stage::Parse);
+        info!("{}", tr!("开始解密[{}]文件", self.fullfilename.yellow()));
+        let Prepared {
+            result,
+            key_data,
+            image_data,
+            music_data,
+        } = self.prepare(outputdir, options)?;
+        let path = &result.path;
+        if result.action == SaveAction::Skipped {
+            info!(
+                "{}",
+                tr!(
+                    "[{}]输出文件已存在，跳过: {}",
+                    self.fullfilename.yellow(),
+                    path.to_str().unwrap().bright_cyan()
+                )
             );
+            return Ok(result);
+        }
+        debug!("{}", tr!("文件名：{}", path.file_name().unwrap().to_str().unwrap().yellow()));
+        //链级创建输出目录
+        if fs::create_dir_all(path.parent().unwrap()).is_err() {
+            return Err(NcmError::FileWriteError);
+        }
 
-            // let filename = standardize_filename(filename);
-            debug!("文件名：{}", filename.yellow());
-            //链级创建输出目录
-            match fs::create_dir_all(outputdir){Err(_)=>return Err(NcmError::FileWriteError),_=>()};
-            outputdir.join(filename)
+        // 处理封面，失败时使用原始封面
+        self.enter(Stage::Cover);
+        let image_data = match cover::process(&image_data, &options.cover) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("{}", tr!("[{}]封面处理失败，使用原始封面: {}", self.filename.yellow(), e));
+                image_data
+            }
         };
 
-        debug!("文件路径: {:?}", path);
-        self.save(&path, music_data)?;
+        //解密音乐数据
+        self.enter(Stage::Decrypt);
+        let music_data = match music_data {
+            Some(data) => data,
+            None => self.decrypt_music(&key_data),
+        };
 
-        {
-            // 保存封面
-            let mut tag = match Tag::new().read_from_path(&path){
-                Ok(o)=>o,
-                Err(_)=>return Err(NcmError::CoverCannotSave)
-            };
-            let cover = Picture {
-                mime_type: MimeType::Jpeg,
-                data: &image_data,
-            };
-            tag.set_album_cover(cover); //添加封面
-            let _ = tag.write_to_path(&path.to_str().unwrap()); //保存
+        self.enter(Stage::Write);
+        debug!("{}", tr!("文件路径: {}", format!("{:?}", path)));
+        // 根据处理之后的封面重新决定另存的路径
+        let cover_path = options.cover_file.as_ref().and_then(|pattern| {
+            cover_file_path(path, pattern, &result.metadata, &image_data, false)
+        });
+        if options.cover_file.is_some() && cover_path.is_none() {
+            debug!("{}", tr!("[{}]没有可以另存的封面", self.filename.yellow()));
         }
+        // 另存的封面也在音乐文件重命名之前写入，失败时不会留下音乐文件
+        write_atomic::<NcmError, _>(path, |temp| {
+            self.save(temp, &music_data, &image_data)?;
+            if let Some(cover_path) = &cover_path {
+                save_cover_file(cover_path, &image_data, options)?;
+            }
+            Ok(())
+        })?;
 
         info!(
-            "[{}] 文件已保存到: {}",
-            self.filename.yellow(),
-            path.to_str().unwrap().bright_cyan()
+            "{}",
+            tr!(
+                "[{}] 文件已保存到: {}",
+                self.filename.yellow(),
+                path.to_str().unwrap().bright_cyan()
+            )
         );
         info!(
             "[{}]{}",
             self.fullfilename.yellow(),
-            "解密成功".bright_green()
+            tr!("解密成功").bright_green()
         );
-        Ok(())
+        Ok(DumpResult {
+            cover_path,
+            ..result
+        })
     }
-    fn save(&mut self, path: &PathBuf, data: Vec<u8>)->Result<(),NcmError> {
-        let music_file = match File::create(path){
-            Ok(o)=>o,
-            Err(_)=>return Err(NcmError::FileWriteError)
+
+    /// 进入新的解密阶段
+    fn enter(&mut self, stage: Stage) {
+        logger::set_stage(Some(stage.as_str()));
+        if let Some(Hook(hook)) = &mut self.stage {
+            hook(stage);
+        }
+    }
+
+    /// 只计算输出文件的路径和处理方式，不写入任何文件
+    pub fn plan(&mut self, outputdir: &Path, options: &DumpOptions) -> Result<DumpResult, NcmError> {
+        self.prepare(outputdir, options).map(|prepared| prepared.result)
+    }
+
+    /// 读取文件头，根据模板和覆盖策略决定输出文件
+    fn prepare(&mut self, outputdir: &Path, options: &DumpOptions) -> Result<Prepared, NcmError> {
+        let NcmHeader {
+            key_data,
+            meta: meta_data,
+            image_data,
+            ..
+        } = self.read_header()?;
+        let metadata = Metadata::from_value(&meta_data);
+        let (format, music_data) = self.detect_format(&metadata, &key_data);
+
+        //处理文件路径
+        trace!("拼接文件路径");
+        let path = self.output_path(outputdir, &metadata, &format, options);
+        let (action, path) = self.resolve_collision(path, options);
+        // 封面处理之前按原始封面推算，转换为JPEG时后缀名总是jpg；跳过的文件不另存封面
+        let cover_path = match &options.cover_file {
+            Some(pattern) if action != SaveAction::Skipped => {
+                cover_file_path(&path, pattern, &metadata, &image_data, options.cover.jpeg)
+            }
+            _ => None,
+        };
+        Ok(Prepared {
+            result: DumpResult {
+                action,
+                path,
+                format,
+                metadata,
+                cover_path,
+            },
+            key_data,
+            image_data,
+            music_data,
+        })
+    }
+
+    /// 判断音乐格式
+    ///
+    /// 元信息中没有格式时需要先解密音乐数据才能判断，同时返回解密后的数据以免重复解密
+    fn detect_format(&mut self, metadata: &Metadata, key_data: &[u8]) -> (String, Option<Vec<u8>>) {
+        match metadata.format.as_str() {
+            "" => {
+                let data = self.decrypt_music(key_data);
+                let format = music_format(&data).unwrap_or("mp3");
+                (format.to_string(), Some(data))
+            }
+            format => (format.to_string(), None),
+        }
+    }
+
+    /// 计算默认的输出路径，不考虑已经存在的文件
+    pub fn target_path(
+        &mut self,
+        outputdir: &Path,
+        options: &DumpOptions,
+    ) -> Result<PathBuf, NcmError> {
+        let header = self.read_header()?;
+        let metadata = Metadata::from_value(&header.meta);
+        let (format, _) = self.detect_format(&metadata, &header.key_data);
+        Ok(self.output_path(outputdir, &metadata, &format, options))
+    }
+
+    /// 根据覆盖策略处理已经存在的输出文件
+    ///
+    /// 本次运行中已经分配给其他源文件的路径不会被覆盖，防止多个线程写入同一个文件：
+    /// 覆盖策略为skip时跳过，否则改为在文件名后添加数字。
+    /// 同一个源文件再次解密时（例如监视模式下文件被修改）仍按覆盖策略处理
+    pub fn resolve_collision(&self, path: PathBuf, options: &DumpOptions) -> (SaveAction, PathBuf) {
+        let mut reserved = options.reserved_paths.lock().unwrap();
+        if reserved
+            .get(&path)
+            .is_some_and(|source| *source != self.source)
+        {
+            warn!(
+                "{}",
+                tr!(
+                    "[{}]与本次运行中的其他文件输出到同一路径: {}",
+                    self.fullfilename.yellow(),
+                    path.to_string_lossy()
+                )
+            );
+            if options.overwrite == OverwritePolicy::Skip {
+                return (SaveAction::Skipped, path);
+            }
+            let renamed = numbered_path(&path, |path| path.exists() || reserved.contains_key(path));
+            reserved.insert(renamed.clone(), self.source.clone());
+            return (SaveAction::Renamed, renamed);
+        }
+        if !path.exists() {
+            reserved.insert(path.clone(), self.source.clone());
+            return (SaveAction::Created, path);
+        }
+        // 源文件有变化时，不论覆盖策略如何都要重新生成上次的输出文件
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if options.redo_outputs.lock().unwrap().contains(&canonical) {
+            reserved.insert(path.clone(), self.source.clone());
+            return (SaveAction::Overwritten, path);
+        }
+        let action = match options.overwrite {
+            OverwritePolicy::Skip => SaveAction::Skipped,
+            OverwritePolicy::Overwrite => SaveAction::Overwritten,
+            OverwritePolicy::Newer => {
+                // 源文件比输出文件新时才覆盖
+                let source = self.file.metadata().and_then(|m| m.modified()).ok();
+                let output = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                match (source, output) {
+                    (Some(source), Some(output)) if source <= output => SaveAction::Skipped,
+                    _ => SaveAction::Overwritten,
+                }
+            }
+            OverwritePolicy::Rename => {
+                let renamed =
+                    numbered_path(&path, |path| path.exists() || reserved.contains_key(path));
+                reserved.insert(renamed.clone(), self.source.clone());
+                return (SaveAction::Renamed, renamed);
+            }
+        };
+        reserved.insert(path.clone(), self.source.clone());
+        (action, path)
+    }
+    /// 计算输出文件的路径
+    ///
+    /// 指定了模板时按模板生成，否则与源文件同名
+    pub fn output_path(
+        &self,
+        outputdir: &Path,
+        metadata: &Metadata,
+        format: &str,
+        options: &DumpOptions,
+    ) -> PathBuf {
+        let relative = match &options.template {
+            Some(template) => template.render(metadata, format, &self.filename),
+            None => PathBuf::from(&self.filename),
+        };
+        // 不能用with_extension，文件名中可能本来就有点
+        let mut filename = relative.file_name().unwrap().to_os_string();
+        filename.push(".");
+        filename.push(format);
+        outputdir.join(relative.with_file_name(filename))
+    }
+
+    /// 写入音乐数据和封面标签
+    fn save(&self, path: &Path, data: &[u8], image_data: &[u8]) -> Result<(), NcmError> {
+        let music_file = match File::create(path) {
+            Ok(o) => o,
+            Err(_) => return Err(NcmError::FileWriteError),
         };
         let mut writer = BufWriter::new(music_file);
-        let _ = writer.write_all(&data);
+        if writer.write_all(data).is_err() {
+            return Err(NcmError::FileWriteError);
+        }
         // 关闭文件
-        match writer.flush(){
-            Ok(o)=>o,
-            Err(_)=>return Err(NcmError::FileWriteError)
+        match writer.flush() {
+            Ok(o) => o,
+            Err(_) => return Err(NcmError::FileWriteError),
         };
+        drop(writer);
+
+        // 保存封面，封面被移除的文件没有封面数据
+        if !image_data.is_empty() {
+            let mut tag = match Tag::new().read_from_path(path) {
+                Ok(o) => o,
+                Err(_) => return Err(NcmError::CoverCannotSave),
+            };
+            let cover = Picture {
+                mime_type: cover_mime(image_data).unwrap_or(MimeType::Jpeg),
+                data: image_data,
+            };
+            tag.set_album_cover(cover); //添加封面
+            if tag.write_to_path(path.to_str().unwrap()).is_err() {
+                return Err(NcmError::CoverCannotSave);
+            }
+        }
         Ok(())
     }
+
+    /// 替换或移除封面，写入新的NCM文件
+    ///
+    /// 只改写封面区块（封面帧长度、图片大小、图片数据），密钥、元信息和音乐数据原样复制。
+    /// 校验码的含义没有确认，也原样保留
+    /// - cover 新的封面数据，为None时移除封面
+    /// - path 新文件的路径，可以与原文件相同
+    pub fn replace_cover(&mut self, cover: Option<&[u8]>, path: &Path) -> Result<(), NcmError> {
+        let header = self.read_header()?;
+        let cover = cover.unwrap_or(&[]);
+        if !cover.is_empty() && cover_mime(cover).is_none() {
+            return Err(NcmError::CoverFormatError);
+        }
+
+        // 封面区块之前的数据原样保留
+        self.position = 0;
+        let head = self.seekread(header.cover_offset)?;
+
+        // 原文件可以直接被覆盖，失败时原文件保持不变
+        write_atomic(path, |temp| {
+            let result = (|| -> std::io::Result<()> {
+                let mut writer = BufWriter::new(File::create(temp)?);
+                writer.write_all(&head)?;
+                // 新的封面之后没有填充，封面帧就是图片本身，校验码按新的图片重新计算
+                writer.write_all(&crc32fast::hash(cover).to_le_bytes())?;
+                writer.write_all(&[header.cover_flag])?;
+                writer.write_all(&(cover.len() as u32).to_le_bytes())?;
+                writer.write_all(&(cover.len() as u32).to_le_bytes())?;
+                writer.write_all(cover)?;
+                let mut reader = BufReader::new(&self.file);
+                reader.seek(SeekFrom::Start(header.music_offset))?;
+                std::io::copy(&mut reader, &mut writer)?;
+                writer.flush()
+            })();
+            match result {
+                Ok(_) => Ok(()),
+                Err(_) => Err(NcmError::FileWriteError),
+            }
+        })
+    }
+}
+
+/// 解密的阶段
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// 读取文件头、元信息和封面
+    Parse,
+    /// 处理封面
+    Cover,
+    /// 解密音乐数据
+    Decrypt,
+    /// 写入输出文件
+    Write,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::Cover => "cover",
+            Stage::Decrypt => "decrypt",
+            Stage::Write => "write",
+        }
+    }
+}
+
+/// 对输出文件的处理方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaveAction {
+    /// 新建文件
+    Created,
+    /// 覆盖了已存在的文件
+    Overwritten,
+    /// 已存在同名文件，添加了数字后缀
+    Renamed,
+    /// 已存在同名文件，跳过
+    Skipped,
+}
+
+/// 解密结果
+#[derive(Debug, Clone)]
+pub struct DumpResult {
+    /// 对输出文件的处理方式
+    pub action: SaveAction,
+    /// 输出文件的路径
+    pub path: PathBuf,
+    /// 音乐格式
+    pub format: String,
+    /// 歌曲元信息
+    pub metadata: Metadata,
+    /// 另存的封面的路径，不另存封面或者没有封面时为None
+    pub cover_path: Option<PathBuf>,
+}
+
+/// 决定了输出文件之后，继续解密需要的数据
+struct Prepared {
+    result: DumpResult,
+    key_data: Vec<u8>,
+    image_data: Vec<u8>,
+    /// 为了判断格式已经解密的音乐数据
+    music_data: Option<Vec<u8>>,
+}
+
+/// 解密选项
+#[derive(Clone, Default)]
+pub struct DumpOptions {
+    /// 另存封面的文件名模板，为None时不另存封面
+    pub cover_file: Option<String>,
+    /// 封面处理选项
+    pub cover: CoverOptions,
+    /// 输出文件名模板，为None时与源文件同名
+    pub template: Option<Template>,
+    /// 输出文件已存在时的处理方式
+    pub overwrite: OverwritePolicy,
+    /// 增量转换时源文件发生了变化的输出文件，总是覆盖
+    pub redo_outputs: Arc<Mutex<HashSet<PathBuf>>>,
+    /// 本次运行已经分配出去的输出路径，以及使用该路径的源文件
+    pub reserved_paths: Arc<Mutex<HashMap<PathBuf, PathBuf>>>,
+    /// 本次运行已经另存过的封面
+    pub saved_covers: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// 文件头部的解析结果
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct NcmHeader {
+    /// RC4密钥，已去掉neteasecloudmusic
+    pub key_data: Vec<u8>,
+    /// json格式的元信息
+    pub meta: Value,
+    /// 封面区块（从校验码开始）在文件中的位置
+    pub cover_offset: u64,
+    /// 封面帧的CRC32校验码
+    pub crc32: u32,
+    /// 校验码之后用途不明的1个字节
+    pub cover_flag: u8,
+    /// 封面帧的长度，包括图片和之后的填充
+    pub cover_frame_length: u32,
+    /// 封面数据
+    pub image_data: Vec<u8>,
+    /// 音乐数据在文件中的位置
+    pub music_offset: u64,
+}
+
+/// 歌曲信息，由`info`子命令输出
+#[derive(Serialize, Debug)]
+pub struct NcmInfo {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    /// 封面大小，单位为字节
+    #[serde(rename = "coverSize")]
+    pub cover_size: usize,
+    /// 封面的MIME类型，没有封面或无法识别时为空
+    #[serde(rename = "coverType")]
+    pub cover_type: Option<String>,
 }
 
 /// 存储元数据的结构体
-#[derive(Serialize, Deserialize, Debug)]
-#[allow(unused_variables, dead_code)]
-struct Metadata {
+///
+/// 网易云不同版本写入的字段类型并不统一（编号可能是数字也可能是字符串），
+/// 所以不直接反序列化，而是从json数据中逐个读取
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Metadata {
     //编号
-    #[serde(rename = "musicId", skip)] //没用过，跳过
-    music_id: String,
+    #[serde(rename = "musicId")]
+    pub music_id: String,
     // 音乐名称
     #[serde(rename = "musicName")]
-    music_name: String,
+    pub music_name: String,
     // 艺术家
     #[serde(rename = "artist")]
-    music_artist: Vec<(String, String)>,
+    pub music_artist: Vec<String>,
     // 专辑id
     #[serde(rename = "albumId")]
-    album_id: String,
+    pub album_id: String,
     // 专辑
     #[serde(rename = "album")]
-    album: String,
-    //
-    #[serde(rename = "albumPicDocId", skip)]
-    album_pic_doc_id: String,
-    //
-    #[serde(rename = "albumPic", skip)]
-    album_pic: String,
+    pub album: String,
     // 比特率
     #[serde(rename = "bitrate")]
-    bitrate: u128,
-    //
-    #[serde(rename = "mp3DocId", skip)]
-    mp3_doc_id: String,
-    // 时间长短
+    pub bitrate: u64,
+    // 时间长短，单位为毫秒
     #[serde(rename = "duration")]
-    duration: u128,
+    pub duration: u64,
     //
     #[serde(rename = "mvId")]
-    mv_id: String,
+    pub mv_id: String,
     // 别名
     #[serde(rename = "alias")]
-    alias: Vec<String>,
+    pub alias: Vec<String>,
     // 译名
     #[serde(rename = "transNames")]
-    trans_names: Vec<String>,
+    pub trans_names: Vec<String>,
     // 音乐格式
     #[serde(rename = "format")]
-    format: String,
+    pub format: String,
+}
+
+impl Metadata {
+    pub fn from_value(data: &Value) -> Metadata {
+        // 数字和字符串统一转为字符串
+        let text = |value: Option<&Value>| match value {
+            Some(Value::String(s)) => s.to_owned(),
+            Some(Value::Number(n)) => n.to_string(),
+            _ => String::new(),
+        };
+        let number = |value: Option<&Value>| match value {
+            Some(Value::Number(n)) => n.as_u64().unwrap_or(0),
+            Some(Value::String(s)) => s.parse().unwrap_or(0),
+            _ => 0,
+        };
+        let list = |value: Option<&Value>| match value {
+            Some(Value::Array(a)) => a.iter().map(|v| text(Some(v))).collect(),
+            _ => Vec::new(),
+        };
+        Metadata {
+            music_id: text(data.get("musicId")),
+            music_name: text(data.get("musicName")),
+            // 艺术家的格式为 [[名称, 编号], ...]
+            music_artist: match data.get("artist") {
+                Some(Value::Array(a)) => a.iter().map(|v| text(v.get(0))).collect(),
+                _ => Vec::new(),
+            },
+            album_id: text(data.get("albumId")),
+            album: text(data.get("album")),
+            bitrate: number(data.get("bitrate")),
+            duration: number(data.get("duration")),
+            mv_id: text(data.get("mvId")),
+            alias: list(data.get("alias")),
+            trans_names: list(data.get("transNames")),
+            format: text(data.get("format")),
+        }
+    }
 }
 
 // 存储各种密钥的结构体
@@ -447,7 +989,7 @@ pub struct Key {
 fn convert_to_generic_arrays(input: &[u8]) -> Vec<GenericArray<u8, U16>> {
     // 确保输入的长度是16的倍数
     assert!(
-        input.len() % 16 == 0,
+        input.len().is_multiple_of(16),
         "Input length must be a multiple of 16"
     );
 
@@ -471,14 +1013,14 @@ fn aes128(key: &[u8], blocks: &[u8]) -> String {
     let mut blocks = convert_to_generic_arrays(blocks);
 
     // 初始化密钥
-    let cipher = Aes128::new(&key);
+    let cipher = Aes128::new(key);
 
     // 开始解密
     cipher.decrypt_blocks(&mut blocks);
 
     let mut x = String::new();
     for block in blocks.iter() {
-        x.push_str(std::str::from_utf8(&block).unwrap())
+        x.push_str(std::str::from_utf8(block).unwrap())
     }
     // 去除所有空格及控制字符
     let x = x[..].trim();
@@ -494,7 +1036,7 @@ fn aes128_to_slice(key: &[u8], blocks: &[u8]) -> Vec<u8> {
     let mut blocks = convert_to_generic_arrays(blocks);
 
     // 初始化密钥
-    let cipher = Aes128::new(&key);
+    let cipher = Aes128::new(key);
 
     // 开始解密
     cipher.decrypt_blocks(&mut blocks);
@@ -514,23 +1056,134 @@ fn aes128_to_slice(key: &[u8], blocks: &[u8]) -> Vec<u8> {
 /// 符号一一对应：
 /// -  \  /  *  ?  "  :   <  >  |
 /// -  _  _  ＊  ？ ＂  ：  ⟨  ⟩   _
-#[allow(dead_code)]
-fn standardize_filename(old_fullfilename: String) -> String {
+pub fn standardize_filename(old_fullfilename: String) -> String {
     trace!("格式化文件名");
-    let mut new_fullfilename = String::from(old_fullfilename);
+    let mut new_fullfilename = old_fullfilename;
     // debug!("规范文件名：{}", new_fullfilename);
     let standard = ["\\", "/", "*", "?", "\"", ":", "<", ">", "|"];
     let resolution = ["_", "_", "＊", "？", "＂", "：", "⟨", "⟩", "_"];
     for i in 0..standard.len() {
         new_fullfilename =
-            new_fullfilename.replace(&standard[i].to_string(), &resolution[i].to_string());
+            new_fullfilename.replace(standard[i], resolution[i]);
     }
     new_fullfilename
 }
 
+/// 在文件名后添加数字，直到找到没有被占用的文件名
+fn numbered_path(path: &Path, exists: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path.file_stem().unwrap().to_os_string();
+    let extension = path.extension().map(|e| e.to_os_string());
+    let mut count = 1;
+    loop {
+        let mut filename = stem.clone();
+        filename.push(format!(" ({})", count));
+        if let Some(extension) = &extension {
+            filename.push(".");
+            filename.push(extension);
+        }
+        let renamed = path.with_file_name(filename);
+        if !exists(&renamed) {
+            return renamed;
+        }
+        count += 1;
+    }
+}
+
+/// 根据文件头判断封面的图片格式
+pub fn cover_mime(data: &[u8]) -> Option<MimeType> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(MimeType::Jpeg)
+    } else if data.starts_with(&[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some(MimeType::Png)
+    } else {
+        None
+    }
+}
+
+/// 根据文件头判断音乐数据的格式
+pub fn music_format(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"fLaC") {
+        Some("flac")
+    } else if data.starts_with(b"ID3") || (data.len() > 1 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+        Some("mp3")
+    } else {
+        None
+    }
+}
+
+/// 根据FLAC的STREAMINFO计算音乐数据最少需要的大小
+///
+/// 帧数不少于总采样数除以最大块大小，每一帧不小于最小帧长度。
+/// STREAMINFO中没有记录总采样数或最小帧长度时返回None
+fn flac_min_size(data: &[u8]) -> Option<u64> {
+    // 第一个元数据块必须是STREAMINFO
+    if data.get(4)? & 0x7F != 0 {
+        return None;
+    }
+    let info = data.get(8..26)?;
+    let max_block = u16::from_be_bytes([info[2], info[3]]) as u64;
+    let min_frame = u32::from_be_bytes([0, info[4], info[5], info[6]]) as u64;
+    let samples = u64::from_be_bytes(info[10..18].try_into().unwrap()) & 0xF_FFFF_FFFF;
+    if max_block == 0 || min_frame == 0 || samples == 0 {
+        return None;
+    }
+    Some(samples.div_ceil(max_block) * min_frame)
+}
+
+/// 计算另存的封面的路径
+///
+/// 图片保存在输出文件所在的文件夹，文件名由模板生成，后缀名根据图片格式决定，
+/// `jpeg`为true时总是jpg。没有封面时返回None
+/// - path 输出文件的路径
+/// - pattern 文件名模板，支持{filename}与{album}
+fn cover_file_path(
+    path: &Path,
+    pattern: &str,
+    metadata: &Metadata,
+    image_data: &[u8],
+    jpeg: bool,
+) -> Option<PathBuf> {
+    let extension = match cover_mime(image_data)? {
+        MimeType::Png if !jpeg => "png",
+        _ => "jpg",
+    };
+    let name = pattern
+        .replace("{filename}", path.file_stem().unwrap().to_str().unwrap())
+        .replace("{album}", &metadata.album);
+    Some(path.with_file_name(format!("{}.{}", standardize_filename(name), extension)))
+}
+
+/// 将封面另存为图片文件
+///
+/// 同一次运行中相同路径的封面只写入一次，所以同一专辑的多首歌只会生成一张图片
+fn save_cover_file(
+    cover_path: &Path,
+    image_data: &[u8],
+    options: &DumpOptions,
+) -> Result<(), NcmError> {
+    // 检查与记录在同一把锁内完成，避免多个线程同时写入同一张图片
+    let mut saved = options.saved_covers.lock().unwrap();
+    if saved.contains(cover_path) {
+        return Ok(());
+    }
+    write_atomic(cover_path, |temp| match fs::write(temp, image_data) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(NcmError::CoverCannotSave),
+    })?;
+    debug!("{}", tr!("封面已保存到: {}", format!("{:?}", cover_path)));
+    saved.insert(cover_path.to_path_buf());
+    Ok(())
+}
+
 /// 使用PKCS5Padding标准，去掉填充信息
-fn unpad(data: &[u8]) -> Vec<u8> {
-    data[..data.len() - data[data.len() - 1] as usize].to_vec()
+///
+/// 填充信息不合法时返回None
+fn unpad(data: &[u8]) -> Option<Vec<u8>> {
+    let pad = *data.last()? as usize;
+    if pad == 0 || pad > 16 || pad > data.len() {
+        return None;
+    }
+    Some(data[..data.len() - pad].to_vec())
 }
 
 #[derive(Debug)]
@@ -540,6 +1193,10 @@ pub enum NcmError {
     CannotReadFileName,
     CannotReadMetaInfo,
     CoverCannotSave,
+    CoverFormatError,
+    KeyError,
+    MusicDataError,
+    FileTruncated,
     FileReadError,
     FileSkipError,
     FileWriteError,
@@ -549,18 +1206,49 @@ pub enum NcmError {
 
 impl std::error::Error for NcmError {}
 
+impl From<std::io::Error> for NcmError {
+    fn from(_: std::io::Error) -> Self {
+        NcmError::FileWriteError
+    }
+}
+
+impl NcmError {
+    /// 稳定的错误代码，用于报告等需要被程序解析的输出
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotNcmFile => "not_ncm_file",
+            Self::CannotReadFileName => "cannot_read_file_name",
+            Self::CannotReadMetaInfo => "cannot_read_meta_info",
+            Self::CoverCannotSave => "cover_cannot_save",
+            Self::CoverFormatError => "cover_format_error",
+            Self::KeyError => "key_error",
+            Self::MusicDataError => "music_data_error",
+            Self::FileTruncated => "file_truncated",
+            Self::FileReadError => "file_read_error",
+            Self::FileSkipError => "file_skip_error",
+            Self::FileWriteError => "file_write_error",
+            Self::FullFilenameError => "full_filename_error",
+            Self::FileNotFoundError => "file_not_found",
+        }
+    }
+}
+
 impl std::fmt::Display for NcmError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::NotNcmFile => write!(f, "该文件不为NCM格式"),
-            Self::CannotReadFileName => write!(f, "无法读取文件名称"),
-            Self::CannotReadMetaInfo => write!(f, "无法读取歌曲元信息"),
-            Self::CoverCannotSave => write!(f, "封面无法保存"),
-
-            Self::FileReadError => write!(f, "读取文件时发生错误"),
-            Self::FileWriteError => write!(f, "写入文件时错误"),
-            Self::FullFilenameError => write!(f, "文件名不符合规范"),
-            _ => write!(f, "未知错误"),
+            Self::NotNcmFile => f.write_str(tr!("该文件不为NCM格式")),
+            Self::CannotReadFileName => f.write_str(tr!("无法读取文件名称")),
+            Self::CannotReadMetaInfo => f.write_str(tr!("无法读取歌曲元信息")),
+            Self::CoverCannotSave => f.write_str(tr!("封面无法保存")),
+            Self::CoverFormatError => f.write_str(tr!("封面不是JPEG或PNG格式")),
+            Self::KeyError => f.write_str(tr!("无法解密RC4密钥")),
+            Self::MusicDataError => f.write_str(tr!("无法识别解密后的音乐数据")),
+            Self::FileTruncated => f.write_str(tr!("文件不完整")),
+
+            Self::FileReadError => f.write_str(tr!("读取文件时发生错误")),
+            Self::FileWriteError => f.write_str(tr!("写入文件时错误")),
+            Self::FullFilenameError => f.write_str(tr!("文件名不符合规范")),
+            _ => f.write_str(tr!("未知错误")),
         }
     }
 }
@@ -585,3 +1273,56 @@ impl TimeCompare {
         time - self.0
     }
 }
+
+/// 已经分配的临时文件数量，用于生成不重复的临时文件名
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// 输出文件的临时路径
+///
+/// 与输出文件在同一个文件夹中，重命名时不会跨文件系统。以`.`开头避免被其他程序扫描到，
+/// 并保留后缀名，写入标签时需要根据后缀名判断格式。
+/// 文件名中包含进程号和计数，同时写入同一个目标的线程或进程不会使用同一个临时文件
+fn temp_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let id = format!(
+        "{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!(
+            ".{}.ncmmiao-tmp-{}.{}",
+            stem,
+            id,
+            extension.to_string_lossy()
+        )),
+        None => path.with_file_name(format!(".{}.ncmmiao-tmp-{}", stem, id)),
+    }
+}
+
+/// 先写入临时文件，同步到磁盘后再重命名为目标文件
+///
+/// 任何一步失败都会删除临时文件，目标文件保持原样
+pub fn write_atomic<E, F>(path: &Path, write: F) -> Result<(), E>
+where
+    E: From<std::io::Error>,
+    F: FnOnce(&Path) -> Result<(), E>,
+{
+    let temp = temp_path(path);
+    signal::track_temp(&temp);
+    let result = write(&temp).and_then(|_| {
+        let synced = fs::OpenOptions::new()
+            .write(true)
+            .open(&temp)
+            .and_then(|file| file.sync_all());
+        match synced.and_then(|_| fs::rename(&temp, path)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(E::from(e)),
+        }
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    signal::untrack_temp(&temp);
+    result
+}
@@ -0,0 +1,158 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    /// 所有进度条都画在这里，stderr不是终端时自动隐藏
+    static ref MULTI: MultiProgress = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+}
+
+/// 输出日志时暂时擦掉进度条，避免日志和进度条混在一起
+pub fn suspend<F: FnOnce() -> R, R>(f: F) -> R {
+    MULTI.suspend(f)
+}
+
+/// 批量解密的进度
+///
+/// 一个总进度条显示完成的文件数和字节数，每个线程一个进度条显示正在解密的文件
+pub struct Progress {
+    total: ProgressBar,
+    /// 空闲的线程进度条
+    idle: Mutex<Vec<ProgressBar>>,
+    files: AtomicUsize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(max_workers: usize) -> Arc<Progress> {
+        let total = MULTI.add(ProgressBar::new(0));
+        total.set_style(
+            ProgressStyle::with_template(
+                "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+        );
+        let style = ProgressStyle::with_template("  {prefix:.dim} {wide_msg} {bytes:>10}/{total_bytes:10}")
+            .unwrap();
+        let idle = (0..max_workers)
+            .map(|i| {
+                let bar = MULTI.add(ProgressBar::new(0));
+                bar.set_style(style.clone());
+                bar.set_prefix(format!("[{}]", i));
+                bar.set_message(tr!("空闲"));
+                bar
+            })
+            .collect();
+        let progress = Arc::new(Progress {
+            total,
+            idle: Mutex::new(idle),
+            files: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        });
+        progress.update_message();
+        progress
+    }
+
+    /// 不显示的进度，试运行时使用
+    pub fn hidden() -> Arc<Progress> {
+        Arc::new(Progress {
+            total: ProgressBar::hidden(),
+            idle: Mutex::new(Vec::new()),
+            files: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        })
+    }
+
+    /// 有新的文件加入队列
+    pub fn add_file(&self, size: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.total.inc_length(size);
+        self.update_message();
+    }
+
+    /// 开始解密一个文件，占用一个空闲的线程进度条
+    pub fn start(self: &Arc<Self>, name: &str, size: u64) -> FileProgress {
+        let bar = self.idle.lock().unwrap().pop().unwrap_or_else(ProgressBar::hidden);
+        bar.reset();
+        bar.set_length(size);
+        bar.set_message(name.to_string());
+        FileProgress {
+            progress: Arc::clone(self),
+            bar,
+            size,
+            counted: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// 清除所有进度条
+    pub fn finish(&self) {
+        for bar in self.idle.lock().unwrap().iter() {
+            bar.finish_and_clear();
+        }
+        self.total.finish_and_clear();
+    }
+
+    /// 总进度条已经处理的字节数和总字节数
+    #[cfg(test)]
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.total.position(), self.total.length().unwrap_or(0))
+    }
+
+    fn update_message(&self) {
+        let failed = self.failed.load(Ordering::Relaxed);
+        let message = tr!(
+            "完成{}/{}",
+            self.done.load(Ordering::Relaxed) + failed,
+            self.files.load(Ordering::Relaxed)
+        );
+        if failed > 0 {
+            self.total.set_message(tr!("{}，失败{}", message, failed));
+        } else {
+            self.total.set_message(message);
+        }
+    }
+}
+
+/// 一个正在解密的文件的进度
+pub struct FileProgress {
+    progress: Arc<Progress>,
+    bar: ProgressBar,
+    size: u64,
+    /// 已经计入总进度的字节数
+    counted: Arc<AtomicU64>,
+}
+
+impl FileProgress {
+    /// 解密过程中的回调，参数为新处理的字节数
+    pub fn hook(&self) -> impl FnMut(u64) + Send + 'static {
+        let bar = self.bar.clone();
+        let total = self.progress.total.clone();
+        let counted = Arc::clone(&self.counted);
+        move |bytes| {
+            bar.inc(bytes);
+            total.inc(bytes);
+            counted.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// 文件处理结束，剩下的字节（文件头、封面等）一并计入总进度，并归还线程进度条
+    pub fn finish(self, success: bool) {
+        let counted = self.counted.load(Ordering::Relaxed);
+        self.progress.total.inc(self.size.saturating_sub(counted));
+        if success {
+            self.progress.done.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.progress.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.progress.update_message();
+        self.bar.reset();
+        self.bar.set_length(0);
+        self.bar.set_message(tr!("空闲"));
+        self.progress.idle.lock().unwrap().push(self.bar);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::discover::InputFile;
+use crate::ncmdump::{self, Metadata, NcmError, SaveAction};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// 失败列表的默认名称，保存在输出文件夹中
+pub const FAILED_LIST_FILENAME: &str = "ncmmiao-failed.txt";
+
+/// 报告的格式版本，字段有不兼容的变化时增加
+const REPORT_VERSION: u32 = 1;
+
+/// 单个文件的处理结果
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Created,
+    Overwritten,
+    Renamed,
+    Skipped,
+    /// 增量转换时没有变化
+    Unchanged,
+    Failed,
+    /// 因为中断而没有处理
+    Cancelled,
+}
+
+impl From<SaveAction> for FileStatus {
+    fn from(action: SaveAction) -> Self {
+        match action {
+            SaveAction::Created => FileStatus::Created,
+            SaveAction::Overwritten => FileStatus::Overwritten,
+            SaveAction::Renamed => FileStatus::Renamed,
+            SaveAction::Skipped => FileStatus::Skipped,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorReport {
+    /// 错误代码，见`NcmError::code`
+    pub code: String,
+    pub message: String,
+}
+
+impl From<&NcmError> for ErrorReport {
+    fn from(e: &NcmError) -> Self {
+        ErrorReport {
+            code: e.code().to_string(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// 歌曲信息摘要
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetadataSummary {
+    pub title: String,
+    pub artist: Vec<String>,
+    pub album: String,
+    pub music_id: String,
+}
+
+impl From<&Metadata> for MetadataSummary {
+    fn from(metadata: &Metadata) -> Self {
+        MetadataSummary {
+            title: metadata.music_name.clone(),
+            artist: metadata.music_artist.clone(),
+            album: metadata.album.clone(),
+            music_id: metadata.music_id.clone(),
+        }
+    }
+}
+
+/// 报告中的一个文件
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReport {
+    /// 源文件的绝对路径
+    pub path: String,
+    pub status: FileStatus,
+    pub error: Option<ErrorReport>,
+    /// 输出文件路径
+    pub output: Option<String>,
+    /// 输出文件夹，重试时使用
+    #[serde(default)]
+    pub output_dir: Option<String>,
+    /// 音乐格式
+    pub format: Option<String>,
+    pub metadata: Option<MetadataSummary>,
+    /// 源文件大小
+    pub bytes_in: u64,
+    /// 输出文件大小，没有写入文件时为0
+    pub bytes_out: u64,
+    /// 处理用时，毫秒
+    pub duration_ms: u64,
+}
+
+impl FileReport {
+    /// 没有经过解密的文件，路径会转换为绝对路径
+    pub fn new(path: &str, status: FileStatus) -> FileReport {
+        FileReport {
+            path: absolute(Path::new(path)),
+            status,
+            error: None,
+            output: None,
+            output_dir: None,
+            format: None,
+            metadata: None,
+            bytes_in: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            bytes_out: 0,
+            duration_ms: 0,
+        }
+    }
+}
+
+/// 各种结果的数量
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Totals {
+    pub discovered: usize,
+    pub created: usize,
+    pub overwritten: usize,
+    pub renamed: usize,
+    pub skipped: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// 一次运行的报告
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Report {
+    pub version: u32,
+    /// 开始和结束的时间，RFC 3339格式
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: u64,
+    /// 是否被中断
+    pub interrupted: bool,
+    pub totals: Totals,
+    pub files: Vec<FileReport>,
+}
+
+impl Report {
+    /// 根据每个文件的结果生成报告，文件按路径排序
+    pub fn new(
+        started_at: chrono::DateTime<chrono::Local>,
+        interrupted: bool,
+        mut files: Vec<FileReport>,
+    ) -> Report {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut totals = Totals {
+            discovered: files.len(),
+            ..Default::default()
+        };
+        for file in &files {
+            match file.status {
+                FileStatus::Created => totals.created += 1,
+                FileStatus::Overwritten => totals.overwritten += 1,
+                FileStatus::Renamed => totals.renamed += 1,
+                FileStatus::Skipped => totals.skipped += 1,
+                FileStatus::Unchanged => totals.unchanged += 1,
+                FileStatus::Failed => totals.failed += 1,
+                FileStatus::Cancelled => totals.cancelled += 1,
+            }
+            totals.bytes_in += file.bytes_in;
+            totals.bytes_out += file.bytes_out;
+        }
+        let finished_at = chrono::Local::now();
+        Report {
+            version: REPORT_VERSION,
+            started_at: started_at.to_rfc3339(),
+            finished_at: finished_at.to_rfc3339(),
+            duration_ms: (finished_at - started_at).num_milliseconds().max(0) as u64,
+            interrupted,
+            totals,
+            files,
+        }
+    }
+
+    /// 写入报告文件，先写入临时文件再重命名
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        ncmdump::write_atomic(path, |temp| fs::write(temp, &data))
+    }
+}
+
+/// 转换为绝对路径，不要求路径存在
+pub fn absolute(path: &Path) -> String {
+    std::path::absolute(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+/// 写入失败列表，每行一个源文件的绝对路径和输出文件夹，用制表符分隔
+///
+/// 每次运行都会覆盖已有的列表，没有失败的文件时删除它，不会留下上次运行的结果。
+/// 被中断时还有没处理的文件，这时保留已有的列表
+pub fn save_failed_list(path: &Path, report: &Report) -> std::io::Result<usize> {
+    let failed: Vec<_> = report
+        .files
+        .iter()
+        .filter(|file| file.status == FileStatus::Failed)
+        .collect();
+    if failed.is_empty() {
+        if !report.interrupted && path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(0);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    ncmdump::write_atomic(path, |temp| -> std::io::Result<()> {
+        let mut file = fs::File::create(temp)?;
+        writeln!(file, "# ncmmiao {}", report.finished_at)?;
+        for entry in &failed {
+            match &entry.output_dir {
+                Some(output_dir) => writeln!(file, "{}\t{}", entry.path, output_dir)?,
+                None => writeln!(file, "{}", entry.path)?,
+            }
+        }
+        Ok(())
+    })?;
+    Ok(failed.len())
+}
+
+/// 重试的来源
+pub enum Retry {
+    /// `--report`生成的报告
+    Report(Vec<InputFile>),
+    /// 每行一个路径的失败列表，路径后面可以有用制表符分隔的输出文件夹
+    List(Vec<InputFile>),
+}
+
+/// 读取上次运行的报告或失败列表，返回其中失败的文件
+///
+/// 报告和失败列表中都记录了每个文件的输出文件夹，重试时沿用，
+/// 因此`--layout mirror`的目录结构也能保留。
+/// 失败列表每行一个路径，空行和以`#`开头的行会被忽略
+pub fn load_failed(path: &Path) -> std::io::Result<Retry> {
+    let data = fs::read(path)?;
+    if let Ok(report) = serde_json::from_slice::<Report>(&data) {
+        if report.version != REPORT_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                tr!("不支持的报告版本: {}", report.version),
+            ));
+        }
+        return Ok(Retry::Report(
+            report
+                .files
+                .into_iter()
+                .filter(|file| file.status == FileStatus::Failed)
+                .map(|file| InputFile {
+                    path: file.path,
+                    subdir: PathBuf::new(),
+                    output: file.output_dir.map(PathBuf::from),
+                })
+                .collect(),
+        ));
+    }
+    Ok(Retry::List(
+        String::from_utf8_lossy(&data)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split_once('\t') {
+                Some((path, output_dir)) => InputFile {
+                    path: path.to_string(),
+                    subdir: PathBuf::new(),
+                    output: Some(PathBuf::from(output_dir)),
+                },
+                None => InputFile {
+                    path: line.to_string(),
+                    subdir: PathBuf::new(),
+                    output: None,
+                },
+            })
+            .collect(),
+    ))
+}
@@ -0,0 +1,72 @@
+use crate::exitcode;
+use colored::Colorize;
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, error, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// 正在写入的临时文件，强制退出时删除
+    static ref TEMP_FILES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// 处理Ctrl-C和SIGTERM
+///
+/// 第一次只做标记，不再开始新的任务，正在解密的文件会正常完成；
+/// 第二次删除还没写完的临时文件后直接退出
+pub fn install() {
+    let result = ctrlc::set_handler(|| {
+        if interrupt() {
+            error!("{}", tr!("强制退出"));
+            remove_temp_files();
+            std::process::exit(exitcode::INTERRUPTED as i32);
+        }
+        warn!(
+            "{}",
+            tr!("收到中断信号，正在等待解密中的文件完成。再按一次Ctrl-C强制退出").bright_yellow()
+        );
+    });
+    if let Err(e) = result {
+        warn!("{}", tr!("无法处理中断信号: {}", e));
+    }
+}
+
+/// 标记收到了中断信号，之前已经收到过时返回true
+pub fn interrupt() -> bool {
+    INTERRUPTED.swap(true, Ordering::SeqCst)
+}
+
+/// 清除中断标记，测试之间互不影响
+#[cfg(test)]
+pub fn reset() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// 删除还没写完的临时文件
+pub fn remove_temp_files() {
+    for path in TEMP_FILES.lock().unwrap().drain() {
+        debug!("{}", tr!("删除临时文件: {}", format!("{:?}", path)));
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// 是否收到了中断信号
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// 记录正在写入的临时文件
+pub fn track_temp(path: &Path) {
+    TEMP_FILES.lock().unwrap().insert(path.to_path_buf());
+}
+
+/// 临时文件已经被重命名或删除
+pub fn untrack_temp(path: &Path) {
+    TEMP_FILES.lock().unwrap().remove(path);
+}
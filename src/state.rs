@@ -0,0 +1,173 @@
+use crate::ncmdump;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// 状态文件的默认名称，保存在输出文件夹中
+pub const STATE_FILENAME: &str = ".ncmmiao-state.json";
+
+/// 状态文件的格式版本
+const STATE_VERSION: u32 = 1;
+
+/// 一个源文件的转换记录
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// 文件大小
+    pub size: u64,
+    /// 修改时间，毫秒时间戳
+    pub mtime: u64,
+    /// 文件内容的CRC32
+    pub hash: String,
+    /// 输出文件的路径
+    pub output: String,
+}
+
+impl Entry {
+    /// 读取源文件的信息，计算哈希时不需要持有状态的锁
+    pub fn new(source: &str, output: &Path) -> Option<Entry> {
+        let (size, mtime) = stat(source)?;
+        let hash = file_hash(source)?;
+        let output = fs::canonicalize(output).unwrap_or_else(|_| output.to_path_buf());
+        Some(Entry {
+            size,
+            mtime,
+            hash,
+            output: output.to_string_lossy().to_string(),
+        })
+    }
+}
+
+/// 源文件相对于上次运行的状态
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// 没有记录
+    New,
+    /// 与记录一致，并且输出文件仍然存在
+    Unchanged,
+    /// 有记录，但源文件发生了变化或者输出文件不见了
+    Changed(Entry),
+}
+
+/// 增量转换的状态数据库
+#[derive(Serialize, Deserialize, Debug)]
+pub struct State {
+    version: u32,
+    /// 以源文件的绝对路径为键
+    files: BTreeMap<String, Entry>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            version: STATE_VERSION,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+impl State {
+    /// 读取状态文件，文件不存在或损坏时返回空的状态
+    pub fn load(path: &Path) -> State {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => {
+                debug!("{}", tr!("状态文件不存在: {}", format!("{:?}", path)));
+                return State::default();
+            }
+        };
+        match serde_json::from_slice::<State>(&data) {
+            Ok(state) if state.version == STATE_VERSION => state,
+            _ => {
+                warn!("{}", tr!("状态文件无法识别，将重新转换所有文件: {}", format!("{:?}", path)));
+                State::default()
+            }
+        }
+    }
+
+    /// 保存状态文件，先写入临时文件再重命名
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        ncmdump::write_atomic(path, |temp| fs::write(temp, &data))
+    }
+
+    /// 判断源文件是否需要重新转换
+    ///
+    /// 大小和修改时间都没有变化时直接认为没有变化，否则比较文件内容
+    pub fn status(&mut self, source: &str) -> Status {
+        let key = key(source);
+        let entry = match self.files.get_mut(&key) {
+            Some(entry) => entry,
+            None => return Status::New,
+        };
+        if !Path::new(&entry.output).exists() {
+            return Status::Changed(entry.clone());
+        }
+        let (size, mtime) = match stat(source) {
+            Some(stat) => stat,
+            None => return Status::Changed(entry.clone()),
+        };
+        if size == entry.size && mtime == entry.mtime {
+            return Status::Unchanged;
+        }
+        // 只有修改时间变了，比如文件被复制过
+        match file_hash(source) {
+            Some(hash) if size == entry.size && hash == entry.hash => {
+                entry.mtime = mtime;
+                Status::Unchanged
+            }
+            _ => Status::Changed(entry.clone()),
+        }
+    }
+
+    /// 记录转换成功的文件
+    pub fn insert(&mut self, source: &str, entry: Entry) {
+        self.files.insert(key(source), entry);
+    }
+
+    /// 清空所有记录
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// 统一使用绝对路径作为键
+fn key(source: &str) -> String {
+    fs::canonicalize(source)
+        .unwrap_or_else(|_| PathBuf::from(source))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// 读取文件大小和修改时间
+fn stat(source: &str) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(source).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_millis() as u64;
+    Some((metadata.len(), mtime))
+}
+
+/// 计算文件内容的CRC32
+fn file_hash(source: &str) -> Option<String> {
+    let mut reader = BufReader::new(File::open(source).ok()?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 0x8000];
+    loop {
+        let n = reader.read(&mut buf).ok()?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Some(format!("{:08x}", hasher.finalize()))
+}
@@ -0,0 +1,148 @@
+use crate::ncmdump::{standardize_filename, Metadata};
+use std::path::PathBuf;
+
+/// 单个文件名最长的字节数，留出后缀名的位置
+const MAX_COMPONENT_LENGTH: usize = 240;
+
+/// 模板中可以使用的字段
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Title,
+    Artist,
+    AlbumArtist,
+    Album,
+    MusicId,
+    AlbumId,
+    Bitrate,
+    Format,
+    Filename,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "title" => Some(Field::Title),
+            "artist" => Some(Field::Artist),
+            "albumartist" => Some(Field::AlbumArtist),
+            "album" => Some(Field::Album),
+            "musicId" => Some(Field::MusicId),
+            "albumId" => Some(Field::AlbumId),
+            "bitrate" => Some(Field::Bitrate),
+            "format" => Some(Field::Format),
+            "filename" => Some(Field::Filename),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Field(Field),
+}
+
+/// 输出文件名模板
+///
+/// 例如`{albumartist}/{album}/{artist} - {title}`。`/`用来分隔文件夹，
+/// 每一级的名称都会被规范化，后缀名由程序自动添加
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// 按`/`拆分后的每一级
+    components: Vec<Vec<Token>>,
+    /// 多个艺术家之间的分隔符
+    separator: String,
+}
+
+impl Template {
+    /// 解析模板，出现未知字段或空模板时返回错误信息
+    pub fn parse(template: &str, separator: &str) -> Result<Template, String> {
+        let mut components = Vec::new();
+        for component in template.split(['/', '\\']) {
+            if component.is_empty() {
+                continue;
+            }
+            let mut tokens = Vec::new();
+            let mut rest = component;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    tokens.push(Token::Literal(rest[..start].to_string()));
+                }
+                let end = match rest[start..].find('}') {
+                    Some(end) => start + end,
+                    None => return Err(tr!("模板中的括号没有闭合: {}", component)),
+                };
+                let name = &rest[start + 1..end];
+                match Field::from_name(name) {
+                    Some(field) => tokens.push(Token::Field(field)),
+                    None => return Err(tr!("模板中有未知字段: {{{}}}", name)),
+                }
+                rest = &rest[end + 1..];
+            }
+            if !rest.is_empty() {
+                tokens.push(Token::Literal(rest.to_string()));
+            }
+            components.push(tokens);
+        }
+        if components.is_empty() {
+            return Err(tr!("模板不能为空").to_string());
+        }
+        Ok(Template {
+            components,
+            separator: separator.to_string(),
+        })
+    }
+
+    /// 根据元信息生成相对路径，不包含后缀名
+    /// - format 音乐格式，元信息中没有格式时是根据音乐数据判断的格式
+    /// - filename 源文件名称，不带后缀
+    pub fn render(&self, metadata: &Metadata, format: &str, filename: &str) -> PathBuf {
+        let mut path = PathBuf::new();
+        for tokens in &self.components {
+            let mut name = String::new();
+            for token in tokens {
+                match token {
+                    Token::Literal(s) => name.push_str(s),
+                    Token::Field(field) => {
+                        name.push_str(&self.field(*field, metadata, format, filename))
+                    }
+                }
+            }
+            path.push(clean_component(name));
+        }
+        path
+    }
+
+    fn field(&self, field: Field, metadata: &Metadata, format: &str, filename: &str) -> String {
+        match field {
+            Field::Title => metadata.music_name.clone(),
+            Field::Artist => metadata.music_artist.join(&self.separator),
+            // 元信息中没有专辑艺术家，使用第一位艺术家
+            Field::AlbumArtist => metadata.music_artist.first().cloned().unwrap_or_default(),
+            Field::Album => metadata.album.clone(),
+            Field::MusicId => metadata.music_id.clone(),
+            Field::AlbumId => metadata.album_id.clone(),
+            Field::Bitrate => (metadata.bitrate / 1000).to_string(),
+            Field::Format => format.to_string(),
+            Field::Filename => filename.to_string(),
+        }
+    }
+}
+
+/// 让每一级名称都是合法的文件名
+fn clean_component(name: String) -> String {
+    let name = standardize_filename(name);
+    // Windows不允许文件名以空格或点结尾
+    let mut name = name.trim().trim_end_matches('.').to_string();
+    if name.len() > MAX_COMPONENT_LENGTH {
+        let mut end = MAX_COMPONENT_LENGTH;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    if name.is_empty() {
+        String::from("_")
+    } else {
+        name
+    }
+}
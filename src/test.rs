@@ -1,6 +1,11 @@
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// 中断标记和正在写入的临时文件是全局的，设置中断和写入输出文件的测试不能同时运行
+    static SIGNAL: Mutex<()> = Mutex::new(());
 
     #[test]
     #[ignore = "测验成功"]
@@ -11,4 +16,1425 @@ mod tests {
         p.execute(|| println!("do new job3"));
         p.execute(|| println!("do new job4"));
     }
+
+    #[test]
+    fn music_format() {
+        assert_eq!(ncmdump::music_format(b"fLaC\0\0\0\x22"), Some("flac"));
+        assert_eq!(ncmdump::music_format(b"ID3\x04\0"), Some("mp3"));
+        assert_eq!(ncmdump::music_format(&[0xFF, 0xFB, 0x90]), Some("mp3"));
+        assert_eq!(ncmdump::music_format(b"CTENFDAM"), None);
+    }
+
+    #[test]
+    fn template() {
+        let metadata = ncmdump::Metadata {
+            music_name: String::from("A/B: C?"),
+            music_artist: vec![String::from("甲"), String::from("乙")],
+            album: String::from(".."),
+            format: String::from("flac"),
+            ..Default::default()
+        };
+        let t = template::Template::parse("{albumartist}/{album}/{artist} - {title}", "; ").unwrap();
+        assert_eq!(
+            t.render(&metadata, "flac", "source"),
+            std::path::PathBuf::from("甲/_/甲; 乙 - A_B： C？")
+        );
+        // 元信息中没有格式时使用根据音乐数据判断的格式
+        let t = template::Template::parse("{title}.{format}", ", ").unwrap();
+        let metadata = ncmdump::Metadata {
+            music_name: String::from("Title"),
+            ..Default::default()
+        };
+        assert_eq!(
+            t.render(&metadata, "mp3", "source"),
+            std::path::PathBuf::from("Title.mp3")
+        );
+        assert!(template::Template::parse("{unknown}", ", ").is_err());
+    }
+
+    #[test]
+    fn i18n_catalog() {
+        use std::collections::HashMap;
+        let catalog: HashMap<_, _> = i18n::EN_CATALOG.iter().copied().collect();
+        let placeholders = |s: &str| s.replace("{{", "").replace("}}", "").matches("{}").count();
+        // 代码中所有tr!的消息都要有翻译，并且参数个数一致
+        for entry in std::fs::read_dir("src").unwrap() {
+            let path = entry.unwrap().path();
+            if path.ends_with("test.rs") {
+                continue;
+            }
+            let source = std::fs::read_to_string(path).unwrap();
+            for part in source.split("tr!(").skip(1) {
+                let Some(rest) = part.trim_start().strip_prefix('"') else {
+                    continue;
+                };
+                let msg = &rest[..rest.find('"').unwrap()];
+                let en = catalog.get(msg).unwrap_or_else(|| panic!("缺少翻译: {}", msg));
+                assert_eq!(placeholders(msg), placeholders(en), "{}", msg);
+            }
+        }
+        // 命令行的帮助信息和参数名都要有翻译
+        fn check(command: &::clap::Command, catalog: &HashMap<&str, &str>) {
+            let mut texts: Vec<String> = command.get_about().map(|s| s.to_string()).into_iter().collect();
+            texts.extend(command.get_after_help().map(|s| s.to_string()));
+            for arg in command.get_arguments() {
+                texts.extend(arg.get_help().map(|s| s.to_string()));
+                texts.extend(arg.get_value_names().into_iter().flatten().map(|s| s.to_string()));
+            }
+            for text in texts.iter().filter(|s| !s.is_ascii()) {
+                assert!(catalog.contains_key(text.as_str()), "缺少翻译: {}", text);
+            }
+            for sub in command.get_subcommands() {
+                check(sub, catalog);
+            }
+        }
+        check(&<clap::Cli as ::clap::CommandFactory>::command(), &catalog);
+        assert_eq!(i18n::format("{{{}}}: {}", &[&"a", &1]), "{a}: 1");
+    }
+
+    #[test]
+    fn events_schema() {
+        let stage = events::Event::Stage {
+            path: "/a.ncm",
+            stage: ncmdump::Stage::Decrypt,
+        };
+        assert_eq!(
+            serde_json::to_value(&stage).unwrap(),
+            serde_json::json!({"event": "stage", "path": "/a.ncm", "stage": "decrypt"})
+        );
+        let error = report::ErrorReport {
+            code: String::from("key_error"),
+            message: String::new(),
+        };
+        let failed = events::Event::Failed {
+            path: "/a.ncm",
+            error: Some(&error),
+            duration_ms: 1,
+        };
+        assert_eq!(serde_json::to_value(&failed).unwrap()["error"]["code"], "key_error");
+    }
+
+    #[test]
+    fn log_context() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("log-context");
+        let audio = flac_fixture(1000);
+        let source = dir.join("a.ncm");
+        fs::write(
+            &source,
+            ncm_fixture(&song_meta(audio.len()), &[], 0, &audio),
+        )
+        .unwrap();
+        let line = || {
+            let line = logger::json_line(
+                &log::Record::builder()
+                    .args(format_args!("\x1b[33m{}\x1b[0m", "message"))
+                    .level(log::Level::Warn)
+                    .build(),
+            );
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()
+        };
+        // 上下文是每个线程单独的，在新线程中运行
+        std::thread::spawn(move || {
+            let json = line();
+            assert_eq!(json["level"], "warn");
+            assert_eq!(json["message"], "message");
+            assert!(json["file"].is_null() && json["stage"].is_null() && json["worker"].is_null());
+
+            logger::set_worker(3);
+            logger::set_file(Some("/music/a.ncm"));
+            Ncmfile::new(source.to_str().unwrap())
+                .unwrap()
+                .dump(&dir.join("out"), &Default::default())
+                .unwrap();
+            // 解密时记录最后进入的阶段
+            let json = line();
+            assert_eq!(json["file"], "/music/a.ncm");
+            assert_eq!(json["stage"], "write");
+            assert_eq!(json["worker"], 3);
+
+            // 结束处理文件时清空文件和阶段，保留线程编号
+            logger::set_file(None);
+            let json = line();
+            assert!(json["file"].is_null() && json["stage"].is_null());
+            assert_eq!(json["worker"], 3);
+            fs::remove_dir_all(&dir).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn config_precedence() {
+        let path = std::env::temp_dir().join(format!("ncmmiao-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "workers = 2\ncover_quality = 80\n[profile.phone]\ncover-max-size = 300\ncover-quality = 70\ncover-jpeg = true\n",
+        )
+        .unwrap();
+        let config = config::Config::load(Some(&path), Some("phone")).unwrap();
+        assert!(config::Config::load(Some(&path), Some("nope")).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let command = <clap::Cli as ::clap::CommandFactory>::command();
+        let matches = command.clone().get_matches_from(["ncmmiao", "-w", "3"]);
+        let mut args = config.to_args(&command, &matches).unwrap();
+        args.sort();
+        // 命令行中的-w优先，配置方案覆盖配置文件
+        assert_eq!(args, ["--cover-jpeg", "--cover-max-size=300", "--cover-quality=70"]);
+
+        // 配置文件中打开的开关可以在命令行中用--no-关闭，false会关闭开关
+        std::fs::write(
+            &path,
+            "incremental = true\nskip-hidden = true\ncover-strip = false\n[profile.all]\nskip-hidden = false\n",
+        )
+        .unwrap();
+        let config = config::Config::load(Some(&path), None).unwrap();
+        let profile = config::Config::load(Some(&path), Some("all")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let parse = |config: &config::Config, cli: &[&str]| {
+            let cli: Vec<_> = ["ncmmiao"]
+                .iter()
+                .chain(cli)
+                .map(|arg| arg.to_string())
+                .collect();
+            let matches = command.clone().get_matches_from(&cli);
+            let args = config.to_args(&command, &matches).unwrap();
+            <clap::Cli as ::clap::Parser>::parse_from(cli.into_iter().chain(args)).dump
+        };
+        let dump = parse(&config, &[]);
+        assert!(dump.incremental && dump.skip_hidden && !dump.cover_strip);
+        let dump = parse(&config, &["--no-incremental", "--cover-strip"]);
+        assert!(!dump.incremental && dump.skip_hidden && dump.cover_strip);
+        let dump = parse(&profile, &[]);
+        assert!(dump.incremental && !dump.skip_hidden);
+        let dump = parse(&profile, &["--skip-hidden"]);
+        assert!(dump.skip_hidden);
+    }
+
+    #[test]
+    fn top_level_help() {
+        // 不指定子命令时的解密选项与dump相同，显示在帮助中
+        let help = clap::Cli::localized_command().render_help().to_string();
+        for option in [
+            "--input",
+            "--output",
+            "--template",
+            "--dry-run",
+            "--log-file",
+        ] {
+            assert!(help.contains(option), "{}", option);
+        }
+        assert!(!help.contains("--no-watch"));
+    }
+
+    #[test]
+    fn global_options_before_subcommand() {
+        let command = clap::Cli::localized_command();
+        let parse = |args: &[&str]| {
+            let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+            clap::Cli::matches_from(&command, &args).and_then(|matches| {
+                <clap::Cli as ::clap::FromArgMatches>::from_arg_matches(&matches)
+            })
+        };
+        // 全局选项可以写在子命令之前
+        let cli = parse(&["ncmmiao", "-v", "--lang", "en", "info", "a.ncm"]).unwrap();
+        assert_eq!(cli.verbose, 1);
+        assert!(matches!(cli.lang, Some(i18n::Lang::En)));
+        assert!(matches!(cli.command, Some(clap::Commands::Info { .. })));
+        let cli = parse(&["ncmmiao", "--log-format", "json", "dump", "-i", "in", "-q"]).unwrap();
+        assert_eq!(cli.quiet, 1);
+        match cli.command {
+            Some(clap::Commands::Dump(dump)) => assert_eq!(dump.input, ["in"]),
+            _ => panic!("dump expected"),
+        }
+        // 不指定子命令时的解密选项不能与子命令一起使用
+        let error = parse(&["ncmmiao", "-i", "in", "info", "a.ncm"])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ::clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn discover_filters() {
+        use std::path::Path;
+        let include = ["*live*".to_string()];
+        let exclude = ["demo/**".to_string(), "*.tmp.ncm".to_string()];
+        let mut options = discover::DiscoverOptions::new(&include, &exclude).unwrap();
+        options.max_depth = Some(2);
+        options.skip_hidden = true;
+        assert!(options.accepts(Path::new("a/live.ncm")));
+        assert!(!options.accepts(Path::new("a/studio.ncm")));
+        assert!(!options.accepts(Path::new("a/live.flac")));
+        assert!(!options.accepts(Path::new("demo/live.ncm")));
+        assert!(!options.accepts(Path::new("a/live.tmp.ncm")));
+        assert!(!options.accepts(Path::new(".cache/live.ncm")));
+        assert!(!options.accepts(Path::new("a/b/live.ncm")));
+        assert!(discover::DiscoverOptions::new(&["[".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn discover_walk() {
+        let dir = temp_dir("discover");
+        let input = dir.join("input");
+        for path in [
+            "a.ncm",
+            "b.flac",
+            ".hidden/c.ncm",
+            "sub/d.ncm",
+            "sub/deep/e.ncm",
+        ] {
+            let path = input.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let linked = dir.join("linked");
+        fs::create_dir_all(&linked).unwrap();
+        fs::write(linked.join("f.ncm"), b"").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&linked, input.join("link")).unwrap();
+            std::os::unix::fs::symlink(dir.join("missing"), input.join("dangling")).unwrap();
+        }
+        let walk = |options: &discover::DiscoverOptions| {
+            let mut files: Vec<_> = collect_files(&[input.to_str().unwrap().to_string()], options)
+                .into_iter()
+                .map(|file| {
+                    let relative = Path::new(&file.path).strip_prefix(&input).unwrap();
+                    // 子文件夹与文件所在的文件夹一致
+                    assert_eq!(file.subdir, relative.parent().unwrap());
+                    relative.to_string_lossy().replace('\\', "/")
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        let mut options = discover::DiscoverOptions::default();
+        assert_eq!(
+            walk(&options),
+            [".hidden/c.ncm", "a.ncm", "sub/d.ncm", "sub/deep/e.ncm"]
+        );
+        options.skip_hidden = true;
+        assert_eq!(walk(&options), ["a.ncm", "sub/d.ncm", "sub/deep/e.ncm"]);
+        options.max_depth = Some(2);
+        assert_eq!(walk(&options), ["a.ncm", "sub/d.ncm"]);
+        // 跟随符号链接时进入链接的文件夹，无效的链接只输出警告
+        #[cfg(unix)]
+        {
+            options.follow_links = true;
+            assert_eq!(walk(&options), ["a.ncm", "link/f.ncm", "sub/d.ncm"]);
+        }
+        // 文件夹和其中的文件同时输入时只处理一次
+        let a = input.join("a.ncm").to_str().unwrap().to_string();
+        let nested = input.join("sub/../a.ncm").to_str().unwrap().to_string();
+        let input = [input.to_str().unwrap().to_string(), a.clone(), nested];
+        let files = discover::dedup(collect_files(&input, &options));
+        assert_eq!(
+            files
+                .iter()
+                .filter(|file| file.path.ends_with("a.ncm"))
+                .count(),
+            1
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn path_list() {
+        let dir = temp_dir("path-list");
+        // 换行分隔，兼容CRLF，忽略空行
+        let list = dir.join("list.txt");
+        fs::write(&list, "a.ncm\r\n\r\nsub/b c.ncm\n\n").unwrap();
+        let paths = discover::read_list(list.to_str().unwrap()).unwrap();
+        assert_eq!(paths, ["a.ncm", "sub/b c.ncm"]);
+        assert!(discover::read_list(dir.join("missing.txt").to_str().unwrap()).is_err());
+
+        // 标准输入同样经过parse_list；有`\0`时按`\0`分隔，路径中可以有换行
+        let paths = discover::parse_list(&b"a.ncm\0line\nbreak.ncm\0\0"[..]).unwrap();
+        assert_eq!(paths, ["a.ncm", "line\nbreak.ncm"]);
+        // 不是UTF-8的路径被跳过，不会被替换成其他文件名
+        let paths = discover::parse_list(&b"a.ncm\n\xFF\xFE.ncm\nb.ncm\n"[..]).unwrap();
+        assert_eq!(paths, ["a.ncm", "b.ncm"]);
+        assert!(discover::parse_list(&b""[..]).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_layout() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("layout");
+        let input = dir.join("input");
+        let audio = flac_fixture(1000);
+        for path in ["x.ncm", "Artist/Album/y.ncm"] {
+            let path = input.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, ncm_fixture(&song_meta(audio.len()), &[], 0, &audio)).unwrap();
+        }
+        // 直接输入的文件没有子文件夹
+        let single = dir.join("z.ncm");
+        fs::write(
+            &single,
+            ncm_fixture(&song_meta(audio.len()), &[], 0, &audio),
+        )
+        .unwrap();
+        let inputs = [
+            input.to_str().unwrap().to_string(),
+            single.to_str().unwrap().to_string(),
+        ];
+        let output = dir.join("out");
+        let dump = |layout| {
+            let mut outputs: Vec<_> = collect_files(&inputs, &discover::DiscoverOptions::default())
+                .into_iter()
+                .map(|file| {
+                    let outputdir = file.output_dir(&output, layout);
+                    Ncmfile::new(&file.path)
+                        .unwrap()
+                        .dump(&outputdir, &Default::default())
+                        .unwrap()
+                        .path
+                })
+                .collect();
+            outputs.sort();
+            outputs
+        };
+
+        assert_eq!(
+            dump(clap::Layout::Flat),
+            [
+                output.join("x.flac"),
+                output.join("y.flac"),
+                output.join("z.flac")
+            ]
+        );
+        assert_eq!(
+            dump(clap::Layout::Mirror),
+            [
+                output.join("Artist/Album/y.flac"),
+                output.join("x.flac"),
+                output.join("z.flac")
+            ]
+        );
+        assert_eq!(
+            dump(clap::Layout::Source),
+            [
+                input.join("Artist/Album/y.flac"),
+                input.join("x.flac"),
+                dir.join("z.flac")
+            ]
+        );
+        assert_eq!(fs::read(output.join("Artist/Album/y.flac")).unwrap(), audio);
+
+        // 重试时指定的输出文件夹优先于布局
+        let file = discover::InputFile {
+            path: single.to_str().unwrap().to_string(),
+            subdir: PathBuf::from("ignored"),
+            output: Some(dir.join("retry")),
+        };
+        for layout in [
+            clap::Layout::Flat,
+            clap::Layout::Mirror,
+            clap::Layout::Source,
+        ] {
+            assert_eq!(file.output_dir(&output, layout), dir.join("retry"));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_new_files() {
+        use std::time::{Duration, Instant};
+        let dir = temp_dir("watch");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        let input = [dir.to_str().unwrap().to_string()];
+        let mut options = discover::DiscoverOptions::default();
+        options.skip_hidden = true;
+        let mut watcher = watch::Watcher::new(&input, options, Duration::from_millis(200)).unwrap();
+        assert_eq!(watcher.dir_count(), 1);
+        // 直接输入的文件不会被监视
+        let file = dir.join("a.ncm");
+        fs::write(&file, b"").unwrap();
+        let file_only = [file.to_str().unwrap().to_string()];
+        assert!(watch::Watcher::new(&file_only, Default::default(), Duration::ZERO).is_err());
+        fs::remove_file(&file).unwrap();
+
+        // 等待文件写入完成，最多等待10秒
+        let mut wait = |expected: usize| {
+            let start = Instant::now();
+            let mut files = Vec::new();
+            while files.len() < expected && start.elapsed() < Duration::from_secs(10) {
+                files.extend(watcher.poll());
+            }
+            // 再等待一次，确认没有多余的文件
+            files.extend(watcher.poll());
+            files
+                .into_iter()
+                .map(|file| (file.path, file.subdir))
+                .collect::<Vec<_>>()
+        };
+        let song = fs::canonicalize(dir.join("sub")).unwrap().join("song.ncm");
+        fs::write(&song, b"first").unwrap();
+        fs::write(dir.join("sub/cover.jpg"), b"").unwrap();
+        fs::write(dir.join(".hidden/hidden.ncm"), b"").unwrap();
+        let expected = vec![(song.to_str().unwrap().to_string(), PathBuf::from("sub"))];
+        assert_eq!(wait(1), expected);
+        // 没有变化的文件不会重复解密，内容变化后重新解密
+        assert!(wait(0).is_empty());
+        fs::write(&song, b"second version").unwrap();
+        assert_eq!(wait(1), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupt() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("interrupt");
+        let audio = flac_fixture(1000);
+        let source = dir.join("a.ncm");
+        fs::write(
+            &source,
+            ncm_fixture(&song_meta(audio.len()), &[], 0, &audio),
+        )
+        .unwrap();
+        let output = dir.join("out");
+        fs::create_dir_all(&output).unwrap();
+        fs::write(output.join("a.flac"), b"old").unwrap();
+
+        // 第一次只做标记，第二次才强制退出
+        assert!(!signal::interrupt());
+        assert!(signal::interrupted());
+        assert!(signal::interrupt());
+
+        // 收到中断后，队列中的任务不再执行
+        let dumper = incremental_dumper(&output, state::State::default());
+        let pool = threadpool::Pool::new(1);
+        dumper.submit(
+            &pool,
+            discover::InputFile {
+                path: source.to_str().unwrap().to_string(),
+                subdir: PathBuf::new(),
+                output: None,
+            },
+        );
+        drop(pool);
+        let statuses: Vec<_> = dumper
+            .results
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|file| file.status)
+            .collect();
+        assert_eq!(statuses, [report::FileStatus::Cancelled]);
+        assert_eq!(fs::read(output.join("a.flac")).unwrap(), b"old");
+
+        // 强制退出时删除正在写入的临时文件，原来的输出文件保持不变
+        let unrelated = dir.join("unrelated");
+        fs::write(&unrelated, b"").unwrap();
+        let result = ncmdump::write_atomic::<std::io::Error, _>(&output.join("a.flac"), |temp| {
+            fs::write(temp, b"partial")?;
+            signal::remove_temp_files();
+            assert!(!temp.exists());
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(output.join("a.flac")).unwrap(), b"old");
+        assert!(unrelated.exists());
+        assert_eq!(fs::read_dir(&output).unwrap().count(), 1);
+        signal::reset();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn progress_and_stages() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("progress");
+        let audio = flac_fixture(100_000);
+        let cover = image_fixture(image::ImageFormat::Png, 8, 8);
+        let source = dir.join("a.ncm");
+        fs::write(
+            &source,
+            ncm_fixture(&song_meta(audio.len()), &cover, 0, &audio),
+        )
+        .unwrap();
+
+        // 阶段按顺序进入，解密的字节数等于音乐数据的大小
+        let stages = Arc::new(Mutex::new(Vec::new()));
+        let bytes = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let mut ncm = Ncmfile::new(source.to_str().unwrap()).unwrap();
+        let stage_log = Arc::clone(&stages);
+        ncm.on_stage(move |stage| stage_log.lock().unwrap().push(stage));
+        let counted = Arc::clone(&bytes);
+        ncm.on_progress(move |n| {
+            counted.fetch_add(n, Ordering::Relaxed);
+        });
+        ncm.dump(&dir.join("out"), &Default::default()).unwrap();
+        assert_eq!(
+            *stages.lock().unwrap(),
+            [
+                ncmdump::Stage::Parse,
+                ncmdump::Stage::Cover,
+                ncmdump::Stage::Decrypt,
+                ncmdump::Stage::Write
+            ]
+        );
+        assert_eq!(bytes.load(Ordering::Relaxed), audio.len() as u64);
+
+        // 文件结束时剩下的字节一并计入总进度，失败的文件也计入
+        let progress = progress::Progress::new(1);
+        progress.add_file(100);
+        progress.add_file(50);
+        assert_eq!(progress.bytes(), (0, 150));
+        let file = progress.start("a.ncm", 100);
+        let mut hook = file.hook();
+        hook(30);
+        assert_eq!(progress.bytes(), (30, 150));
+        file.finish(true);
+        assert_eq!(progress.bytes(), (100, 150));
+        // 线程进度条用完时使用隐藏的进度条
+        let first = progress.start("b.ncm", 50);
+        let second = progress.start("c.ncm", 0);
+        second.finish(true);
+        first.finish(false);
+        assert_eq!(progress.bytes(), (150, 150));
+        progress.finish();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_cover() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("replace-cover");
+        let audio = flac_fixture(100_000);
+        let jpeg = [&[0xFF, 0xD8, 0xFF, 0xE0][..], &[7; 300]].concat();
+        let png = [&b"\x89PNG\r\n\x1a\n"[..], &[9; 50]].concat();
+        // 原文件的封面之后有16字节的填充
+        let source = dir.join("a.ncm");
+        fs::write(
+            &source,
+            ncm_fixture(&song_meta(audio.len()), &jpeg, 16, &audio),
+        )
+        .unwrap();
+        let (header, music) = decrypt_fixture(&source);
+        assert_eq!(header.image_data, jpeg);
+        assert_eq!(music, audio);
+
+        let target = dir.join("out.ncm");
+        Ncmfile::new(source.to_str().unwrap())
+            .unwrap()
+            .replace_cover(Some(&png), &target)
+            .unwrap();
+        let (replaced, music) = decrypt_fixture(&target);
+        assert_eq!(replaced.image_data, png);
+        assert_eq!(replaced.crc32, crc32fast::hash(&png));
+        assert_eq!(music, audio);
+        // 加密的音乐数据逐字节相同
+        let (old, new) = (fs::read(&source).unwrap(), fs::read(&target).unwrap());
+        assert_eq!(
+            old[header.music_offset as usize..],
+            new[replaced.music_offset as usize..]
+        );
+
+        // 直接改写原文件
+        Ncmfile::new(target.to_str().unwrap())
+            .unwrap()
+            .replace_cover(None, &target)
+            .unwrap();
+        let (removed, music) = decrypt_fixture(&target);
+        assert!(removed.image_data.is_empty());
+        assert_eq!(removed.crc32, crc32fast::hash(&[]));
+        assert_eq!(music, audio);
+        assert_eq!(ncmdump::music_format(&music), Some("flac"));
+        assert!(Ncmfile::new(target.to_str().unwrap())
+            .unwrap()
+            .replace_cover(Some(b"not an image"), &target)
+            .is_err());
+        // 没有留下临时文件
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_files() {
+        let dir = temp_dir("check");
+        // 最小帧长度1000字节，共40帧，音乐数据至少有40000字节
+        let mut audio = flac_fixture(50_000);
+        audio[12..15].copy_from_slice(&1000u32.to_be_bytes()[1..]);
+        audio[18..26]
+            .copy_from_slice(&(44100u64 << 44 | 1 << 41 | 15 << 36 | (40 * 4096)).to_be_bytes());
+        let cover = [0xFF, 0xD8, 0xFF, 0xE0, 1, 2, 3];
+        let check = |name: &str, data: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            Ncmfile::new(path.to_str().unwrap()).unwrap().check()
+        };
+        let valid = ncm_fixture(&song_meta(audio.len()), &cover, 0, &audio);
+        // 校验码与封面的CRC32不同也不影响
+        assert!(check("valid.ncm", &valid).is_ok());
+
+        // 音乐数据只剩一半
+        let truncated = &valid[..valid.len() - audio.len() / 2];
+        assert!(matches!(
+            check("truncated.ncm", truncated),
+            Err(ncmdump::NcmError::FileTruncated)
+        ));
+        // FLAC的比特率不固定，比元信息估算的大小小很多也不是文件不完整
+        let mut meta = song_meta(audio.len());
+        meta["bitrate"] = serde_json::json!(32000);
+        assert!(check("vbr.ncm", &ncm_fixture(&meta, &cover, 0, &audio)).is_ok());
+
+        // MP3根据比特率和时长估算大小
+        let mp3 = [&[0xFF, 0xFB][..], &[0; 49_998]].concat();
+        let mut meta = song_meta(mp3.len());
+        meta["format"] = serde_json::json!("mp3");
+        assert!(check("mp3.ncm", &ncm_fixture(&meta, &cover, 0, &mp3)).is_ok());
+        let partial = ncm_fixture(&meta, &cover, 0, &mp3[..mp3.len() / 2]);
+        assert!(matches!(
+            check("mp3-truncated.ncm", &partial),
+            Err(ncmdump::NcmError::FileTruncated)
+        ));
+        // 元信息中没有比特率和时长时无法估算大小，不会误报
+        meta.as_object_mut().unwrap().remove("bitrate");
+        meta["duration"] = serde_json::json!(0);
+        let partial = ncm_fixture(&meta, &cover, 0, &mp3[..mp3.len() / 2]);
+        assert!(check("no-bitrate.ncm", &partial).is_ok());
+
+        let mut magic = valid.clone();
+        magic[0] = b'X';
+        assert!(matches!(
+            check("magic.ncm", &magic),
+            Err(ncmdump::NcmError::NotNcmFile)
+        ));
+        let mut key = valid.clone();
+        key[14] ^= 0xFF;
+        assert!(matches!(
+            check("key.ncm", &key),
+            Err(ncmdump::NcmError::KeyError)
+        ));
+        let garbage = ncm_fixture(&song_meta(audio.len()), &cover, 0, &vec![0; audio.len()]);
+        assert!(matches!(
+            check("audio.ncm", &garbage),
+            Err(ncmdump::NcmError::MusicDataError)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_collision() {
+        let dir = temp_dir("collision");
+        let audio = flac_fixture(1000);
+        let mut sources = Vec::new();
+        for name in ["a.ncm", "b.ncm", "c.ncm"] {
+            let path = dir.join(name);
+            fs::write(&path, ncm_fixture(&song_meta(audio.len()), &[], 0, &audio)).unwrap();
+            sources.push(path.to_str().unwrap().to_string());
+        }
+        let output = dir.join("out");
+        // 三个源文件的标题相同，输出到同一个路径
+        let plan = |policy| {
+            let options = ncmdump::DumpOptions {
+                template: Some(template::Template::parse("{title}", ", ").unwrap()),
+                overwrite: policy,
+                ..Default::default()
+            };
+            sources
+                .iter()
+                .map(|source| {
+                    let result = Ncmfile::new(source)
+                        .unwrap()
+                        .plan(&output, &options)
+                        .unwrap();
+                    (result.action, result.path)
+                })
+                .collect::<Vec<_>>()
+        };
+        let song = output.join("Song.flac");
+        for policy in [
+            clap::OverwritePolicy::Overwrite,
+            clap::OverwritePolicy::Newer,
+        ] {
+            assert_eq!(
+                plan(policy),
+                [
+                    (SaveAction::Created, song.clone()),
+                    (SaveAction::Renamed, output.join("Song (1).flac")),
+                    (SaveAction::Renamed, output.join("Song (2).flac")),
+                ]
+            );
+        }
+        assert_eq!(
+            plan(clap::OverwritePolicy::Skip),
+            [
+                (SaveAction::Created, song.clone()),
+                (SaveAction::Skipped, song.clone()),
+                (SaveAction::Skipped, song.clone()),
+            ]
+        );
+
+        // 同一个源文件再次解密时（监视模式下文件被修改）按覆盖策略处理，不会改名
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let options = ncmdump::DumpOptions {
+            overwrite: clap::OverwritePolicy::Overwrite,
+            ..Default::default()
+        };
+        let redump = dir.join("redump");
+        let dump = || {
+            let result = Ncmfile::new(&sources[0])
+                .unwrap()
+                .dump(&redump, &options)
+                .unwrap();
+            (result.action, result.path)
+        };
+        let a = redump.join("a.flac");
+        assert_eq!(dump(), (SaveAction::Created, a.clone()));
+        fs::write(
+            &sources[0],
+            ncm_fixture(&song_meta(audio.len()), &[], 16, &audio),
+        )
+        .unwrap();
+        assert_eq!(dump(), (SaveAction::Overwritten, a.clone()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incremental_state() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("state");
+        let audio = flac_fixture(1000);
+        let a = dir.join("a.ncm");
+        let b = dir.join("b.ncm");
+        for path in [&a, &b] {
+            fs::write(path, ncm_fixture(&song_meta(audio.len()), &[], 0, &audio)).unwrap();
+        }
+        let output = dir.join("out");
+        fs::create_dir_all(&output).unwrap();
+        // b的输出文件已经存在，覆盖策略为skip时跳过
+        fs::write(output.join("b.flac"), b"existing").unwrap();
+        let state_path = output.join(state::STATE_FILENAME);
+        let run = || {
+            let dumper = incremental_dumper(&output, state::State::load(&state_path));
+            let pool = threadpool::Pool::new(1);
+            for path in [&a, &b] {
+                let file = discover::InputFile {
+                    path: path.to_str().unwrap().to_string(),
+                    subdir: PathBuf::new(),
+                    output: None,
+                };
+                if dumper.needs_dump(&file) {
+                    dumper.submit(&pool, file);
+                }
+            }
+            drop(pool);
+            dumper.save_state();
+            let mut results: Vec<_> = dumper
+                .results
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|file| (file.path.clone(), file.status))
+                .collect();
+            results.sort_by(|x, y| x.0.cmp(&y.0));
+            results
+                .into_iter()
+                .map(|(_, status)| status)
+                .collect::<Vec<_>>()
+        };
+        let status = |path: &Path| state::State::load(&state_path).status(path.to_str().unwrap());
+
+        assert_eq!(
+            run(),
+            [report::FileStatus::Created, report::FileStatus::Skipped]
+        );
+        assert_eq!(status(&a), state::Status::Unchanged);
+        // 跳过的文件不是本次生成的，不记录
+        assert_eq!(status(&b), state::Status::New);
+        assert_eq!(fs::read(output.join("b.flac")).unwrap(), b"existing");
+
+        // 只有修改时间变化时比较文件内容
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(status(&a), state::Status::Unchanged);
+        assert_eq!(
+            run(),
+            [report::FileStatus::Unchanged, report::FileStatus::Skipped]
+        );
+
+        // 源文件变化后，即使覆盖策略为skip也要重新生成上次的输出文件
+        let changed = flac_fixture(2000);
+        fs::write(&a, ncm_fixture(&song_meta(changed.len()), &[], 0, &changed)).unwrap();
+        match status(&a) {
+            state::Status::Changed(entry) => {
+                assert_eq!(
+                    PathBuf::from(entry.output),
+                    fs::canonicalize(output.join("a.flac")).unwrap()
+                )
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            run(),
+            [report::FileStatus::Overwritten, report::FileStatus::Skipped]
+        );
+        assert_eq!(fs::read(output.join("a.flac")).unwrap(), changed);
+        assert_eq!(status(&a), state::Status::Unchanged);
+
+        // 输出文件不见了
+        fs::remove_file(output.join("a.flac")).unwrap();
+        assert!(matches!(status(&a), state::Status::Changed(_)));
+
+        // 重建状态时，元信息中没有格式的文件根据音乐数据判断后缀名
+        let mut meta = song_meta(audio.len());
+        meta["format"] = "".into();
+        fs::write(&a, ncm_fixture(&meta, &[], 0, &audio)).unwrap();
+        fs::write(output.join("a.flac"), &audio).unwrap();
+        let files = [discover::InputFile {
+            path: a.to_str().unwrap().to_string(),
+            subdir: PathBuf::new(),
+            output: None,
+        }];
+        let mut rebuilt = state::State::default();
+        rebuild_state(
+            &mut rebuilt,
+            &files,
+            output.to_str().unwrap(),
+            clap::Layout::Flat,
+            None,
+        );
+        assert_eq!(
+            rebuilt.status(a.to_str().unwrap()),
+            state::Status::Unchanged
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_write() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("atomic");
+        let path = dir.join("song.flac");
+        fs::write(&path, b"old").unwrap();
+        // 写入失败时保留原来的文件，并删除临时文件
+        let mut temp = PathBuf::new();
+        let result = ncmdump::write_atomic(&path, |path| {
+            temp = path.to_path_buf();
+            fs::write(path, b"partial")?;
+            Err(ncmdump::NcmError::MusicDataError)
+        });
+        assert!(matches!(result, Err(ncmdump::NcmError::MusicDataError)));
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert!(!temp.exists());
+        // 每次使用不同的临时文件
+        let mut second = PathBuf::new();
+        ncmdump::write_atomic::<std::io::Error, _>(&path, |path| {
+            second = path.to_path_buf();
+            fs::write(path, b"new")
+        })
+        .unwrap();
+        assert_ne!(temp, second);
+        assert_eq!(second.extension().unwrap(), "flac");
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn run_report() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("report");
+        let audio = flac_fixture(1000);
+        let good = dir.join("b.ncm");
+        let data = ncm_fixture(&song_meta(audio.len()), &[], 0, &audio);
+        fs::write(&good, &data).unwrap();
+        let broken = dir.join("a.ncm");
+        let junk = b"not an ncm file";
+        fs::write(&broken, junk).unwrap();
+        let output = dir.join("out");
+        let started_at = chrono::Local::now();
+        let dumper = incremental_dumper(&output, state::State::default());
+        let pool = threadpool::Pool::new(2);
+        for path in [&good, &broken] {
+            dumper.submit(
+                &pool,
+                discover::InputFile {
+                    path: path.to_str().unwrap().to_string(),
+                    subdir: PathBuf::new(),
+                    output: None,
+                },
+            );
+        }
+        drop(pool);
+        let results = std::mem::take(&mut *dumper.results.lock().unwrap());
+        let report = report::Report::new(started_at, false, results);
+
+        // 文件按路径排序，统计与每个文件的状态一致
+        let totals = &report.totals;
+        assert_eq!(
+            (totals.discovered, totals.created, totals.failed),
+            (2, 1, 1)
+        );
+        assert_eq!(totals.bytes_in, (data.len() + junk.len()) as u64);
+        assert_eq!(totals.bytes_out, audio.len() as u64);
+        let [failed, created] = &report.files[..] else {
+            panic!("{:?}", report.files);
+        };
+        assert_eq!(failed.path, report::absolute(&broken));
+        assert_eq!(failed.error.as_ref().unwrap().code, "not_ncm_file");
+        assert!(failed.output.is_none());
+        assert_eq!(
+            created.output,
+            Some(report::absolute(&output.join("b.flac")))
+        );
+        assert_eq!(created.output_dir, Some(report::absolute(&output)));
+        assert_eq!(created.format.as_deref(), Some("flac"));
+        assert_eq!(created.metadata.as_ref().unwrap().title, "Song");
+
+        // JSON中的字段名和状态是稳定的
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["interrupted"], false);
+        assert_eq!(json["totals"]["created"], 1);
+        assert_eq!(json["files"][0]["status"], "failed");
+        assert_eq!(json["files"][1]["status"], "created");
+        assert_eq!(json["files"][1]["metadata"]["artist"][0], "Artist");
+        assert!(chrono::DateTime::parse_from_rfc3339(json["started_at"].as_str().unwrap()).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exit_codes() {
+        use exitcode::*;
+        // 中断优先，之前确定的错误不会被失败的文件覆盖
+        assert_eq!(summary(SUCCESS, false, 0), SUCCESS);
+        assert_eq!(summary(SUCCESS, false, 2), FAILED);
+        assert_eq!(summary(SUCCESS, true, 0), INTERRUPTED);
+        assert_eq!(summary(INVALID_ARGS, true, 1), INTERRUPTED);
+        assert_eq!(summary(INVALID_ARGS, false, 1), INVALID_ARGS);
+        assert_eq!(summary(NOT_FOUND, false, 0), NOT_FOUND);
+
+        // 子命令使用同样的退出码
+        let dir = temp_dir("exit-codes");
+        let audio = flac_fixture(1000);
+        let good = dir.join("good.ncm");
+        fs::write(&good, ncm_fixture(&song_meta(audio.len()), &[], 0, &audio)).unwrap();
+        let broken = dir.join("broken.ncm");
+        fs::write(&broken, b"not an ncm file").unwrap();
+        let empty = dir.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        let input = |paths: &[&Path]| {
+            paths
+                .iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(check(&input(&[&good]), 1), ExitCode::from(SUCCESS));
+        assert_eq!(check(&input(&[&good, &broken]), 1), ExitCode::from(FAILED));
+        assert_eq!(check(&input(&[&empty]), 1), ExitCode::from(NOT_FOUND));
+        assert_eq!(show_info(&input(&[&good]), true), ExitCode::from(SUCCESS));
+        assert_eq!(show_info(&input(&[&broken]), true), ExitCode::from(FAILED));
+        assert_eq!(
+            show_info(&input(&[&empty]), true),
+            ExitCode::from(NOT_FOUND)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retry_round_trip() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("retry");
+        let output_dir = dir.join("out").join("Album");
+        let mut failed = report::FileReport::new("in/Album/a.ncm", report::FileStatus::Failed);
+        failed.output_dir = Some(report::absolute(&output_dir));
+        let mut created = report::FileReport::new("in/b.ncm", report::FileStatus::Created);
+        created.output_dir = Some(report::absolute(&dir.join("out")));
+        let report = report::Report::new(chrono::Local::now(), false, vec![failed, created]);
+        let expected = vec![(
+            report::absolute(Path::new("in/Album/a.ncm")),
+            Some(output_dir),
+        )];
+        let files = |files: Vec<discover::InputFile>| {
+            files
+                .into_iter()
+                .map(|file| (file.path, file.output))
+                .collect::<Vec<_>>()
+        };
+
+        // 报告和失败列表都只包含失败的文件，并保留输出文件夹
+        let report_path = dir.join("report.json");
+        report.save(&report_path).unwrap();
+        match report::load_failed(&report_path).unwrap() {
+            report::Retry::Report(retry) => assert_eq!(files(retry), expected),
+            report::Retry::List(_) => panic!("report loaded as list"),
+        }
+        let list = dir.join(report::FAILED_LIST_FILENAME);
+        assert_eq!(report::save_failed_list(&list, &report).unwrap(), 1);
+        match report::load_failed(&list).unwrap() {
+            report::Retry::List(retry) => assert_eq!(files(retry), expected),
+            report::Retry::Report(_) => panic!("list loaded as report"),
+        }
+
+        // 被中断时保留上次的列表，没有失败时删除它
+        let interrupted = report::Report::new(chrono::Local::now(), true, Vec::new());
+        assert_eq!(report::save_failed_list(&list, &interrupted).unwrap(), 0);
+        assert!(list.exists());
+        let success = report::Report::new(chrono::Local::now(), false, Vec::new());
+        assert_eq!(report::save_failed_list(&list, &success).unwrap(), 0);
+        assert!(!list.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let dir = temp_dir("dry-run");
+        let audio = flac_fixture(1000);
+        let source = dir.join("a.ncm");
+        let cover = image_fixture(image::ImageFormat::Png, 8, 8);
+        fs::write(
+            &source,
+            ncm_fixture(&song_meta(audio.len()), &cover, 0, &audio),
+        )
+        .unwrap();
+        let broken = dir.join("broken.ncm");
+        fs::write(&broken, b"not an ncm file").unwrap();
+        let files = || {
+            [&source, &broken]
+                .into_iter()
+                .map(|path| discover::InputFile {
+                    path: path.to_str().unwrap().to_string(),
+                    subdir: PathBuf::new(),
+                    output: None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let listing = |dir: &Path| {
+            let mut names: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = listing(&dir);
+
+        // 输出文件夹不存在时不会被创建
+        let output = dir.join("out");
+        let mut dumper = incremental_dumper(&output, state::State::default());
+        dumper.options = Arc::new(ncmdump::DumpOptions {
+            cover_file: Some("cover".to_string()),
+            overwrite: clap::OverwritePolicy::Rename,
+            ..Default::default()
+        });
+        let totals = dry_run(&dumper, files());
+        assert_eq!((totals.created, totals.failed), (1, 1));
+        assert!(!output.exists());
+        assert_eq!(listing(&dir), before);
+
+        // 计划中包括另存的封面，转换为JPEG时后缀名为jpg，跳过的文件不另存封面
+        let plan = |options: &ncmdump::DumpOptions| {
+            Ncmfile::new(source.to_str().unwrap())
+                .unwrap()
+                .plan(&output, options)
+                .unwrap()
+                .cover_path
+        };
+        assert_eq!(plan(&dumper.options), Some(output.join("cover.png")));
+        let mut options = ncmdump::DumpOptions {
+            cover_file: Some("{album}".to_string()),
+            cover: cover::CoverOptions {
+                jpeg: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(plan(&options), Some(output.join("Album.jpg")));
+        fs::create_dir_all(&output).unwrap();
+        fs::write(output.join("a.flac"), b"existing").unwrap();
+        options.overwrite = clap::OverwritePolicy::Skip;
+        assert_eq!(plan(&options), None);
+
+        // 输出文件已经存在时只报告重命名，不修改输出文件夹
+        dumper.options = Arc::new(ncmdump::DumpOptions {
+            overwrite: clap::OverwritePolicy::Rename,
+            ..Default::default()
+        });
+        let totals = dry_run(&dumper, files());
+        assert_eq!((totals.renamed, totals.failed), (1, 1));
+        assert_eq!(listing(&output), ["a.flac"]);
+        assert_eq!(fs::read(output.join("a.flac")).unwrap(), b"existing");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cover_process() {
+        use image::{GenericImageView, ImageFormat};
+        let png = image_fixture(ImageFormat::Png, 200, 100);
+        let jpeg = image_fixture(ImageFormat::Jpeg, 200, 100);
+        let decode = |data: &[u8]| {
+            let format = image::guess_format(data).unwrap();
+            let img = image::load_from_memory(data).unwrap();
+            (format, img.dimensions())
+        };
+        let process =
+            |data: &[u8], options: cover::CoverOptions| cover::process(data, &options).unwrap();
+
+        // 没有选项或者不需要处理时原样返回
+        assert_eq!(process(&png, Default::default()), png);
+        let large = cover::CoverOptions {
+            max_size: Some(500),
+            ..Default::default()
+        };
+        assert_eq!(process(&png, large.clone()), png);
+        assert_eq!(process(&jpeg, large), jpeg);
+        assert!(process(
+            &[],
+            cover::CoverOptions {
+                jpeg: true,
+                ..Default::default()
+            }
+        )
+        .is_empty());
+
+        // 等比缩小，保持原来的格式
+        let small = cover::CoverOptions {
+            max_size: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            decode(&process(&png, small.clone())),
+            (ImageFormat::Png, (50, 25))
+        );
+        assert_eq!(
+            decode(&process(&jpeg, small)),
+            (ImageFormat::Jpeg, (50, 25))
+        );
+
+        // 转换为JPEG，质量越低文件越小
+        let to_jpeg = |quality| cover::CoverOptions {
+            jpeg: true,
+            quality,
+            ..Default::default()
+        };
+        let converted = process(&png, to_jpeg(None));
+        assert_eq!(decode(&converted), (ImageFormat::Jpeg, (200, 100)));
+        assert!(process(&png, to_jpeg(Some(10))).len() < process(&png, to_jpeg(Some(100))).len());
+
+        // 去除元数据时重新编码
+        let strip = cover::CoverOptions {
+            strip: true,
+            ..Default::default()
+        };
+        assert_ne!(process(&jpeg, strip.clone()), jpeg);
+        assert_eq!(
+            decode(&process(&jpeg, strip)),
+            (ImageFormat::Jpeg, (200, 100))
+        );
+        assert!(cover::process(b"not an image", &to_jpeg(None)).is_err());
+    }
+
+    #[test]
+    fn extract_cover() {
+        let _signal = SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("extract-cover");
+        let audio = flac_fixture(1000);
+        let jpeg = image_fixture(image::ImageFormat::Jpeg, 16, 16);
+        let png = image_fixture(image::ImageFormat::Png, 16, 16);
+        let sources: Vec<_> = [("a.ncm", &jpeg), ("b.ncm", &jpeg), ("c.ncm", &png)]
+            .into_iter()
+            .map(|(name, cover)| {
+                let path = dir.join(name);
+                fs::write(
+                    &path,
+                    ncm_fixture(&song_meta(audio.len()), cover, 0, &audio),
+                )
+                .unwrap();
+                path
+            })
+            .collect();
+        let dump = |output: &Path, pattern: &str| {
+            let options = ncmdump::DumpOptions {
+                cover_file: Some(pattern.to_string()),
+                ..Default::default()
+            };
+            for source in &sources {
+                Ncmfile::new(source.to_str().unwrap())
+                    .unwrap()
+                    .dump(output, &options)
+                    .unwrap();
+            }
+        };
+
+        // 默认与输出文件同名，后缀名根据图片格式决定
+        let output = dir.join("same-name");
+        dump(&output, "{filename}");
+        assert_eq!(fs::read(output.join("a.jpg")).unwrap(), jpeg);
+        assert_eq!(fs::read(output.join("b.jpg")).unwrap(), jpeg);
+        assert_eq!(fs::read(output.join("c.png")).unwrap(), png);
+        // 封面同时写入音乐文件的标签
+        let tag = audiotags::Tag::new()
+            .read_from_path(output.join("a.flac"))
+            .unwrap();
+        assert_eq!(tag.album_cover().unwrap().data, &jpeg[..]);
+
+        // 同一次运行中相同路径的封面只写入第一张
+        let output = dir.join("album");
+        dump(&output, "{album}");
+        assert_eq!(fs::read(output.join("Album.jpg")).unwrap(), jpeg);
+        assert_eq!(fs::read(output.join("Album.png")).unwrap(), png);
+        let mut names: Vec<_> = fs::read_dir(&output)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["Album.jpg", "Album.png", "a.flac", "b.flac", "c.flac"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 每个测试使用单独的临时文件夹
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ncmmiao-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 使用增量转换的Dumper，状态文件保存在输出文件夹中
+    fn incremental_dumper(output: &Path, state: state::State) -> Dumper {
+        Dumper {
+            outputdir: output.to_path_buf(),
+            layout: clap::Layout::Flat,
+            options: Arc::new(ncmdump::DumpOptions {
+                overwrite: clap::OverwritePolicy::Skip,
+                ..Default::default()
+            }),
+            results: Arc::new(Mutex::new(Vec::new())),
+            state_path: Some(output.join(state::STATE_FILENAME)),
+            state: Arc::new(Mutex::new(state)),
+            state_changed: Arc::new(AtomicBool::new(true)),
+            progress: progress::Progress::new(1),
+        }
+    }
+
+    /// 歌曲元信息，比特率和时长使估算的音乐数据大小正好为size字节
+    fn song_meta(size: usize) -> serde_json::Value {
+        serde_json::json!({
+            "musicId": 1,
+            "musicName": "Song",
+            "artist": [["Artist", 2]],
+            "album": "Album",
+            "albumId": 3,
+            "bitrate": 8000,
+            "duration": size,
+            "format": "flac",
+        })
+    }
+
+    /// 只有STREAMINFO的FLAC文件，后面用0填充到size字节
+    fn flac_fixture(size: usize) -> Vec<u8> {
+        let mut data = b"fLaC\x80\0\0\x22".to_vec();
+        data.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        // 44100Hz，双声道，16位
+        data.extend((44100u64 << 44 | 1 << 41 | 15 << 36).to_be_bytes());
+        data.extend([0; 16]);
+        data.resize(size, 0);
+        data
+    }
+
+    /// 指定格式和尺寸的渐变图片
+    fn image_fixture(format: image::ImageFormat, width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        });
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgb8(img)
+            .write_to(&mut std::io::Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    /// 按照NCM的格式生成文件，音乐数据使用与解密相同的流密钥加密
+    fn ncm_fixture(
+        meta: &serde_json::Value,
+        cover: &[u8],
+        padding: usize,
+        audio: &[u8],
+    ) -> Vec<u8> {
+        use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+        use base64::Engine;
+        let encrypt = |key: &str, data: &[u8]| {
+            let cipher = aes::Aes128::new(GenericArray::from_slice(&hex::decode(key).unwrap()));
+            let pad = 16 - data.len() % 16;
+            let mut data = data.to_vec();
+            data.resize(data.len() + pad, pad as u8);
+            for block in data.chunks_mut(16) {
+                cipher.encrypt_block(GenericArray::from_mut_slice(block));
+            }
+            data
+        };
+        let rc4_key = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let mut key = encrypt(
+            "687A4852416D736F356B496E62617857",
+            &[&b"neteasecloudmusic"[..], rc4_key].concat(),
+        );
+        key.iter_mut().for_each(|byte| *byte ^= 0x64);
+        let meta = encrypt(
+            "2331346C6A6B5F215C5D2630553C2728",
+            format!("music:{}", meta).as_bytes(),
+        );
+        let mut meta = format!(
+            "163 key(Don't modify):{}",
+            base64::engine::general_purpose::STANDARD.encode(meta)
+        )
+        .into_bytes();
+        meta.iter_mut().for_each(|byte| *byte ^= 0x63);
+
+        let mut key_box: Vec<u8> = (0..=255).collect();
+        let mut last = 0usize;
+        for i in 0..256 {
+            let swap = key_box[i];
+            last = (swap as usize + last + rc4_key[i % rc4_key.len()] as usize) & 0xFF;
+            key_box[i] = key_box[last];
+            key_box[last] = swap;
+        }
+        let music: Vec<u8> = audio
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                let j = (i + 1) & 0xFF;
+                let k = key_box[j] as usize;
+                byte ^ key_box[(k + key_box[(k + j) & 0xFF] as usize) & 0xFF]
+            })
+            .collect();
+
+        let u32le = |n: usize| (n as u32).to_le_bytes();
+        let mut file = b"CTENFDAM\x01\x70".to_vec();
+        file.extend(u32le(key.len()));
+        file.extend(key);
+        file.extend(u32le(meta.len()));
+        file.extend(meta);
+        file.extend([0xEF, 0xBE, 0xAD, 0xDE, 0x01]);
+        file.extend(u32le(cover.len() + padding));
+        file.extend(u32le(cover.len()));
+        file.extend(cover);
+        file.extend(vec![0; padding]);
+        file.extend(music);
+        file
+    }
+
+    /// 读取文件头并解密音乐数据
+    fn decrypt_fixture(path: &Path) -> (ncmdump::NcmHeader, Vec<u8>) {
+        let mut ncm = Ncmfile::new(path.to_str().unwrap()).unwrap();
+        let header = ncm.read_header().unwrap();
+        let music = ncm.decrypt_music(&header.key_data);
+        (header, music)
+    }
 }
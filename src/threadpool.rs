@@ -1,3 +1,4 @@
+use crate::logger;
 use log::debug;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{self, JoinHandle};
@@ -15,16 +16,19 @@ struct Worker {
 
 impl Worker {
     fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let t = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-            match message {
-                Message::NewJob(job) => {
-                    debug!("线程[{}]获得任务", id);
-                    job();
-                }
-                Message::ByeBye => {
-                    debug!("线程[{}]结束任务", id);
-                    break;
+        let t = thread::spawn(move || {
+            logger::set_worker(id);
+            loop {
+                let message = receiver.lock().unwrap().recv().unwrap();
+                match message {
+                    Message::NewJob(job) => {
+                        debug!("{}", tr!("线程[{}]获得任务", id));
+                        job();
+                    }
+                    Message::ByeBye => {
+                        debug!("{}", tr!("线程[{}]结束任务", id));
+                        break;
+                    }
                 }
             }
         });
@@ -47,7 +51,7 @@ impl Pool {
         if max_workers == 0 {
             panic!("最大线程数不能小于零！")
         } else {
-            debug!("将开启{}线程", max_workers);
+            debug!("{}", tr!("将开启{}线程", max_workers));
         }
         let (tx, rx) = mpsc::channel();
 
@@ -58,8 +62,8 @@ impl Pool {
         }
 
         Pool {
-            workers: workers,
-            max_workers: max_workers,
+            workers,
+            max_workers,
             sender: tx,
         }
     }
@@ -0,0 +1,187 @@
+use crate::discover::{DiscoverOptions, InputFile};
+#[allow(unused_imports)]
+use log::{debug, trace, warn};
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// 每次等待事件的最长时间
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 还在写入中的文件
+struct Pending {
+    /// 上一次看到的文件大小
+    size: u64,
+    /// 文件大小最后一次变化的时间
+    since: Instant,
+}
+
+/// 监视输入文件夹，找出新出现并且已经写入完成的ncm文件
+pub struct Watcher {
+    /// 需要一直持有，drop之后就不再产生事件
+    _watcher: RecommendedWatcher,
+    rx: Receiver<notify::Result<Event>>,
+    /// 被监视的文件夹，用来计算子文件夹
+    roots: Vec<PathBuf>,
+    pending: HashMap<PathBuf, Pending>,
+    /// 已经交给线程池的文件和当时的大小、修改时间，防止重复解密
+    queued: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    /// 文件大小保持不变多久之后认为写入完成
+    delay: Duration,
+    /// 与批量解密相同的筛选规则
+    options: DiscoverOptions,
+}
+
+impl Watcher {
+    /// 递归监视输入中的所有文件夹，直接输入的文件会被忽略
+    pub fn new(
+        input: &[String],
+        options: DiscoverOptions,
+        delay: Duration,
+    ) -> notify::Result<Watcher> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let mut roots = Vec::new();
+        for arg in input {
+            let path = Path::new(arg);
+            if !path.is_dir() {
+                warn!("{}", tr!("[{}]不是文件夹，不会被监视", arg));
+                continue;
+            }
+            // 使用绝对路径，事件中的路径也会是绝对路径
+            let root = fs::canonicalize(path)?;
+            watcher.watch(&root, RecursiveMode::Recursive)?;
+            debug!("{}", tr!("开始监视: {}", format!("{:?}", root)));
+            roots.push(root);
+        }
+        if roots.is_empty() {
+            return Err(notify::Error::generic(tr!("没有可以监视的文件夹")));
+        }
+        Ok(Watcher {
+            _watcher: watcher,
+            rx,
+            roots,
+            pending: HashMap::new(),
+            queued: HashMap::new(),
+            delay,
+            options,
+        })
+    }
+
+    /// 被监视的文件夹数量
+    pub fn dir_count(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// 等待一段时间，返回已经写入完成的文件，没有时返回空列表
+    pub fn poll(&mut self) -> Vec<InputFile> {
+        match self.rx.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => self.handle(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Vec::new(),
+        }
+        while let Ok(event) = self.rx.try_recv() {
+            self.handle(event)
+        }
+
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        self.pending.retain(|path, pending| {
+            let metadata = match fs::metadata(path) {
+                Ok(metadata) => metadata,
+                // 文件已经被删除或者移走
+                Err(_) => return false,
+            };
+            if metadata.len() != pending.size {
+                pending.size = metadata.len();
+                pending.since = now;
+                return true;
+            }
+            if now.duration_since(pending.since) < self.delay {
+                return true;
+            }
+            ready.push((path.clone(), metadata.len(), metadata.modified().ok()));
+            false
+        });
+
+        let mut files = Vec::new();
+        for (path, size, mtime) in ready {
+            if self.queued.get(&path) == Some(&(size, mtime)) {
+                trace!(
+                    "{}",
+                    tr!("文件没有变化，不再重复解密: {}", format!("{:?}", path))
+                );
+                continue;
+            }
+            self.queued.insert(path.clone(), (size, mtime));
+            files.push(self.input_file(path));
+        }
+        files
+    }
+
+    fn handle(&mut self, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("{}", tr!("监视文件夹时出错: {}", e));
+                return;
+            }
+        };
+        match event.kind {
+            EventKind::Remove(_) => {
+                for path in &event.paths {
+                    self.pending.remove(path);
+                }
+                return;
+            }
+            // 读取文件也会产生事件，只关心写入完成
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {}
+            EventKind::Access(_) => return,
+            _ => {}
+        }
+        for path in event.paths {
+            if !self.accepts(&path) {
+                continue;
+            }
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            trace!("{}", tr!("文件有变化: {}", format!("{:?}", path)));
+            self.pending.insert(
+                path,
+                Pending {
+                    size,
+                    since: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// 按相对于被监视文件夹的路径检查筛选规则
+    fn accepts(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .is_some_and(|relative| self.options.accepts(relative))
+    }
+
+    /// 与批量解密一样，保留被监视文件夹之下的目录结构
+    fn input_file(&self, path: PathBuf) -> InputFile {
+        let subdir = path
+            .parent()
+            .and_then(|parent| {
+                self.roots
+                    .iter()
+                    .find_map(|root| parent.strip_prefix(root).ok())
+            })
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        InputFile {
+            path: path.to_string_lossy().to_string(),
+            subdir,
+            output: None,
+        }
+    }
+}